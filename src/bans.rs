@@ -0,0 +1,379 @@
+use crate::logging::*;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+ * Site bans.  Each entry matches either an address range in CIDR notation
+ * ("10.1.0.0/16", "2001:db8::/32", or a single address), or hostnames from
+ * reverse DNS, with * and ? wildcards ("*.example.com").  A pattern starting
+ * with a dot matches that domain and everything under it (".example.com").
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanType {
+    // Nobody gets in
+    Total,
+    // Existing characters can log in, but no new ones can be made
+    NewChar,
+    // Only immortals can log in
+    ImmortalOnly,
+}
+
+impl BanType {
+    fn severity(&self) -> u8 {
+        match self {
+            BanType::NewChar => 1,
+            BanType::ImmortalOnly => 2,
+            BanType::Total => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanEntry {
+    pub pattern: String,
+    pub ban_type: BanType,
+    pub reason: String,
+    pub banned_by: String,
+    pub created: u64,
+    // Seconds since the epoch, None for a permanent ban
+    pub expires: Option<u64>,
+}
+
+impl BanEntry {
+    #[allow(unused)]
+    pub fn new(pattern: &str, ban_type: BanType, reason: &str, banned_by: &str, duration: Option<u64>) -> Self {
+        let now = now_secs();
+        BanEntry {
+            pattern: pattern.trim().to_lowercase(),
+            ban_type,
+            reason: reason.to_string(),
+            banned_by: banned_by.to_string(),
+            created: now,
+            expires: duration.map(|d| now + d),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now_secs())
+    }
+
+    pub fn matches_ip(&self, ip: IpAddr) -> bool {
+        match parse_cidr(&self.pattern) {
+            Some((network, prefix)) => ip_in_network(ip, network, prefix),
+            None => false,
+        }
+    }
+
+    pub fn matches_hostname(&self, hostname: &str) -> bool {
+        if parse_cidr(&self.pattern).is_some() {
+            return false;
+        }
+
+        let hostname = hostname.trim_end_matches('.').to_lowercase();
+        if self.pattern.starts_with('.') {
+            return hostname == self.pattern[1..] || hostname.ends_with(&self.pattern);
+        }
+
+        glob_match(self.pattern.as_bytes(), hostname.as_bytes())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// "10.0.0.0/8" or a bare address, which is a /32 or /128
+fn parse_cidr(pattern: &str) -> Option<(IpAddr, u8)> {
+    let (address, prefix) = match pattern.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (pattern, None),
+    };
+
+    let address: IpAddr = address.parse().ok()?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse().ok()?,
+        None => max,
+    };
+
+    if prefix > max {
+        return None;
+    }
+    Some((address, prefix))
+}
+
+// Whether the address is in a CIDR range, for other address lists
+pub fn cidr_contains(pattern: &str, ip: IpAddr) -> bool {
+    parse_cidr(pattern.trim()).is_some_and(|(network, prefix)| ip_in_network(ip, network, prefix))
+}
+
+fn ip_in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    // IPv4 clients on a dual stack socket show up as ::ffff:a.b.c.d
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        _ => ip,
+    };
+
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix as u32) };
+            u32::from(ip) & mask == u32::from(network) & mask
+        },
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix as u32) };
+            u128::from(ip) & mask == u128::from(network) & mask
+        },
+        _ => false,
+    }
+}
+
+// Shell style wildcards: * for any run of characters, ? for any one
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last * swallow one more character and try again
+            star = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+
+#[derive(Debug, Clone, Default)]
+struct BanList {
+    path: Option<String>,
+    entries: Vec<BanEntry>,
+}
+
+use lazy_static::lazy_static;
+lazy_static! {
+    static ref BANS: Arc<RwLock<BanList>> = Arc::new(RwLock::new(Default::default()));
+    // Held while the file is written, so saves land in the order the changes were made
+    static ref SAVING: Mutex<()> = Mutex::new(());
+}
+
+/*
+ * (Re)load the ban list from the data directory.  Called at startup and on
+ * reconfigure, so edits to the file take effect on SIGHUP.
+ */
+pub fn load_bans(data_dir: &str) {
+    let path = String::from(Path::new(data_dir).join("bans.json").to_str().unwrap());
+    let mut entries: Vec<BanEntry> = vec![];
+
+    if Path::new(&path).is_file() {
+        let result = fs::read_to_string(&path).map_err(|e| e.to_string())
+            .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()));
+        match result {
+            Ok(loaded) => entries = loaded,
+            Err(e) => {
+                // Keep what we had rather than unbanning everyone
+                log_error(&format!("Couldn't load bans from {}: {}", path, e));
+                BANS.write().unwrap().path = Some(path);
+                return;
+            },
+        }
+    }
+
+    entries.retain(|entry| !entry.is_expired());
+    log_info(&format!("Loaded {} bans from {}", entries.len(), path));
+
+    let mut bans = BANS.write().unwrap();
+    bans.path = Some(path);
+    bans.entries = entries;
+}
+
+/*
+ * Write out the list after a change.  Takes the lock on the list, and only
+ * keeps it long enough to copy the entries, so connections checking for
+ * bans aren't held up by the file being written.
+ */
+fn save_bans(bans: RwLockWriteGuard<BanList>) -> Result<(), String> {
+    let _saving = SAVING.lock().unwrap();
+    let (path, entries) = (bans.path.clone(), bans.entries.clone());
+    drop(bans);
+
+    let Some(path) = path else {
+        return Ok(());
+    };
+    serde_json::to_string_pretty(&entries).map_err(|e| e.to_string())
+        .and_then(|text| fs::write(&path, text).map_err(|e| e.to_string()))
+        .map_err(|e| {
+            log_error(&format!("Couldn't save bans to {}: {}", path, e));
+            format!("{}: {}", path, e)
+        })
+}
+
+// Save hook, so the list on disk is up to date when we shut down
+pub fn save_all_bans() -> Result<(), String> {
+    save_bans(BANS.write().unwrap())
+}
+
+// Replaces any existing ban on the same pattern
+#[allow(unused)]
+pub fn add_ban(entry: BanEntry) {
+    log_info(&format!("Adding {:?} ban on {} by {}: {}", entry.ban_type, entry.pattern, entry.banned_by, entry.reason));
+
+    let mut bans = BANS.write().unwrap();
+    bans.entries.retain(|e| e.pattern != entry.pattern && !e.is_expired());
+    bans.entries.push(entry);
+    let _ = save_bans(bans);
+}
+
+#[allow(unused)]
+pub fn remove_ban(pattern: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let mut bans = BANS.write().unwrap();
+    let count = bans.entries.len();
+    bans.entries.retain(|e| e.pattern != pattern);
+    if bans.entries.len() == count {
+        return false;
+    }
+
+    // The file is being rewritten anyway, so drop anything that has run out
+    bans.entries.retain(|e| !e.is_expired());
+    log_info(&format!("Removed ban on {}", pattern));
+    let _ = save_bans(bans);
+    true
+}
+
+#[allow(unused)]
+pub fn list_bans() -> Vec<BanEntry> {
+    BANS.read().unwrap().entries.iter().filter(|e| !e.is_expired()).cloned().collect()
+}
+
+/*
+ * The most severe ban covering this address, or any of its hostnames.
+ * Hostnames are only known once reverse DNS comes back, so a connection is
+ * checked on its address first and then again with its names.
+ */
+pub fn check_ban(ip: IpAddr, hostnames: &[String]) -> Option<BanEntry> {
+    let bans = BANS.read().unwrap();
+    bans.entries.iter()
+        .filter(|e| !e.is_expired())
+        .filter(|e| e.matches_ip(ip) || hostnames.iter().any(|h| e.matches_hostname(h)))
+        .max_by_key(|e| e.ban_type.severity())
+        .cloned()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(pattern: &str, ban_type: BanType) -> BanEntry {
+        BanEntry::new(pattern, ban_type, "testing", "tester", None)
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let entry = ban("10.1.0.0/16", BanType::Total);
+        assert!(entry.matches_ip(ip("10.1.2.3")));
+        assert!(!entry.matches_ip(ip("10.2.0.1")));
+
+        let entry = ban("2001:db8::/32", BanType::Total);
+        assert!(entry.matches_ip(ip("2001:db8:1::1")));
+        assert!(!entry.matches_ip(ip("2001:db9::1")));
+        assert!(!entry.matches_ip(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let entry = ban("192.0.2.0/24", BanType::Total);
+        assert!(entry.matches_ip(ip("::ffff:192.0.2.7")));
+        assert!(!entry.matches_ip(ip("::ffff:198.51.100.7")));
+    }
+
+    #[test]
+    fn whole_and_single_address_prefixes() {
+        assert!(ban("0.0.0.0/0", BanType::Total).matches_ip(ip("203.0.113.9")));
+        assert!(ban("::/0", BanType::Total).matches_ip(ip("2001:db8::1")));
+
+        let entry = ban("192.0.2.7", BanType::Total);
+        assert!(entry.matches_ip(ip("192.0.2.7")));
+        assert!(!entry.matches_ip(ip("192.0.2.8")));
+
+        let entry = ban("2001:db8::7", BanType::Total);
+        assert!(entry.matches_ip(ip("2001:db8::7")));
+        assert!(!entry.matches_ip(ip("2001:db8::8")));
+
+        assert_eq!(parse_cidr("192.0.2.0/33"), None);
+        assert!(!cidr_contains("not an address", ip("192.0.2.7")));
+    }
+
+    #[test]
+    fn domain_suffix() {
+        let entry = ban(".Example.com", BanType::Total);
+        assert!(entry.matches_hostname("example.com"));
+        assert!(entry.matches_hostname("host.example.com."));
+        assert!(entry.matches_hostname("a.b.EXAMPLE.com"));
+        assert!(!entry.matches_hostname("badexample.com"));
+        assert!(!entry.matches_hostname("example.com.au"));
+    }
+
+    #[test]
+    fn wildcards() {
+        let entry = ban("*.dialup.example.net", BanType::Total);
+        assert!(entry.matches_hostname("ppp-1-2.dialup.example.net"));
+        assert!(!entry.matches_hostname("dialup.example.net"));
+
+        let entry = ban("host?.example.net", BanType::Total);
+        assert!(entry.matches_hostname("host1.example.net"));
+        assert!(!entry.matches_hostname("host12.example.net"));
+
+        assert!(glob_match(b"a*b*c", b"axxbyyc"));
+        assert!(!glob_match(b"a*b*c", b"axxbyy"));
+        assert!(glob_match(b"*", b""));
+
+        // Address patterns never match names
+        assert!(!ban("192.0.2.0/24", BanType::Total).matches_hostname("192.0.2.7"));
+    }
+
+    #[test]
+    fn expiry() {
+        assert!(!ban("192.0.2.7", BanType::Total).is_expired());
+        assert!(BanEntry::new("192.0.2.7", BanType::Total, "", "", Some(0)).is_expired());
+        assert!(!BanEntry::new("192.0.2.7", BanType::Total, "", "", Some(3600)).is_expired());
+    }
+
+    #[test]
+    fn most_severe_ban_wins() {
+        *BANS.write().unwrap() = BanList {
+            path: None,
+            entries: vec![
+                ban("192.0.2.0/24", BanType::NewChar),
+                ban(".example.com", BanType::ImmortalOnly),
+                BanEntry::new("192.0.2.7", BanType::Total, "", "", Some(0)),
+                ban("198.51.100.0/24", BanType::Total),
+            ],
+        };
+
+        let hostnames = vec!["host.example.com".to_string()];
+        assert_eq!(check_ban(ip("192.0.2.7"), &[]).map(|b| b.ban_type), Some(BanType::NewChar));
+        assert_eq!(check_ban(ip("192.0.2.7"), &hostnames).map(|b| b.ban_type), Some(BanType::ImmortalOnly));
+        assert_eq!(check_ban(ip("198.51.100.1"), &hostnames).map(|b| b.ban_type), Some(BanType::Total));
+        assert!(check_ban(ip("203.0.113.1"), &[]).is_none());
+    }
+}
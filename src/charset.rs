@@ -0,0 +1,176 @@
+use crate::telnet::*;
+use serde_derive::{Deserialize, Serialize};
+
+/*
+ * CHARSET (RFC2066) subnegotiation commands
+ */
+pub const CHARSET_REQUEST: u8 = 1;
+pub const CHARSET_ACCEPTED: u8 = 2;
+pub const CHARSET_REJECTED: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+    Ascii,
+}
+
+// In order of preference
+pub const SUPPORTED_ENCODINGS: [Encoding; 3] = [Encoding::Utf8, Encoding::Latin1, Encoding::Ascii];
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_uppercase().as_str() {
+            "UTF-8" | "UTF8" => Some(Encoding::Utf8),
+            "ISO-8859-1" | "ISO_8859-1" | "ISO8859-1" | "LATIN1" | "LATIN-1" => Some(Encoding::Latin1),
+            "US-ASCII" | "ASCII" | "ANSI_X3.4-1968" => Some(Encoding::Ascii),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "ISO-8859-1",
+            Encoding::Ascii => "US-ASCII",
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(data).to_string(),
+            Encoding::Latin1 => data.iter().map(|&b| b as char).collect(),
+            Encoding::Ascii => data.iter().map(|&b| if b < 0x80 { b as char } else { '?' }).collect(),
+        }
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            Encoding::Utf8 => text.as_bytes().to_vec(),
+            Encoding::Latin1 => {
+                let mut b = vec![];
+                for c in text.chars() {
+                    if (c as u32) < 0x100 {
+                        b.push(c as u32 as u8);
+                    } else {
+                        b.extend_from_slice(transliterate(c).as_bytes());
+                    }
+                }
+                b
+            },
+            Encoding::Ascii => {
+                let mut b = vec![];
+                for c in text.chars() {
+                    if c.is_ascii() {
+                        b.push(c as u8);
+                    } else {
+                        b.extend_from_slice(transliterate(c).as_bytes());
+                    }
+                }
+                b
+            },
+        }
+    }
+}
+
+// IAC SB CHARSET REQUEST ";UTF-8;ISO-8859-1;US-ASCII" IAC SE
+pub fn charset_request() -> TelnetCommand {
+    let mut payload = vec![CHARSET_REQUEST];
+    for encoding in SUPPORTED_ENCODINGS.iter() {
+        payload.push(b';');
+        payload.extend_from_slice(encoding.name().as_bytes());
+    }
+    subnegotiation(TELOPT_CHARSET, &payload)
+}
+
+/*
+ * A REQUEST from the client lists charsets after a separator character.
+ * Returns the first one we support, if any.
+ */
+pub fn choose_encoding(payload: &[u8]) -> Option<(Encoding, String)> {
+    let mut list = payload;
+    if list.starts_with(b"[TTABLE]") && list.len() > 9 {
+        // Skip the translation table version byte
+        list = &list[9..];
+    }
+
+    if list.len() < 2 {
+        return None;
+    }
+
+    let separator = list[0] as char;
+    let names = String::from_utf8_lossy(&list[1..]).to_string();
+    for name in names.split(separator) {
+        if let Some(encoding) = Encoding::from_name(name) {
+            return Some((encoding, name.to_string()));
+        }
+    }
+    None
+}
+
+/*
+ * Closest ASCII for characters the client can't display.  Covers accented
+ * Latin letters, box drawing and typographic punctuation.
+ */
+pub fn transliterate(c: char) -> String {
+    let s = match c {
+        'À'..='Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+        'à'..='å' | 'ā' | 'ă' | 'ą' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' | 'Ć' | 'Ĉ' | 'Ċ' | 'Č' => "C",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'Ð' | 'Ď' | 'Đ' => "D",
+        'ð' | 'ď' | 'đ' => "d",
+        'È'..='Ë' | 'Ē' | 'Ĕ' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'è'..='ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'Ĝ' | 'Ğ' | 'Ġ' | 'Ģ' => "G",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'Ì'..='Ï' | 'Ĩ' | 'Ī' | 'Ĭ' | 'Į' | 'İ' => "I",
+        'ì'..='ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'Ł' | 'Ĺ' | 'Ļ' | 'Ľ' => "L",
+        'ł' | 'ĺ' | 'ļ' | 'ľ' => "l",
+        'Ñ' | 'Ń' | 'Ņ' | 'Ň' => "N",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'Ò'..='Ö' | 'Ø' | 'Ō' | 'Ŏ' | 'Ő' => "O",
+        'ò'..='ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Ŕ' | 'Ŗ' | 'Ř' => "R",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'Ś' | 'Ŝ' | 'Ş' | 'Š' => "S",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'Ţ' | 'Ť' => "T",
+        'ţ' | 'ť' => "t",
+        'Þ' => "Th",
+        'þ' => "th",
+        'Ù'..='Ü' | 'Ũ' | 'Ū' | 'Ŭ' | 'Ů' | 'Ű' | 'Ų' => "U",
+        'ù'..='ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'Ý' | 'Ÿ' => "Y",
+        'ý' | 'ÿ' => "y",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ź' | 'ż' | 'ž' => "z",
+        '─' | '━' | '═' | '╌' | '╍' | '┄' | '┅' | '┈' | '┉' => "-",
+        '│' | '┃' | '║' | '╎' | '╏' | '┆' | '┇' | '┊' | '┋' => "|",
+        '\u{250C}'..='\u{254B}' | '\u{2552}'..='\u{256C}' => "+",
+        '\u{2580}'..='\u{259F}' => "#",
+        '‘' | '’' | '‚' | '′' => "'",
+        '“' | '”' | '„' | '″' | '«' | '»' => "\"",
+        '‐' | '‑' | '‒' | '–' | '—' | '―' => "-",
+        '…' => "...",
+        '•' | '·' => "*",
+        '×' => "x",
+        '÷' => "/",
+        '©' => "(c)",
+        '®' => "(R)",
+        '™' => "(TM)",
+        '°' => "deg",
+        '€' => "EUR",
+        '£' => "GBP",
+        '¥' => "JPY",
+        '\u{00A0}' => " ",
+        _ => "?",
+    };
+    s.to_string()
+}
@@ -0,0 +1,112 @@
+use flate2::{Compress, Compression, Decompress, DecompressError, FlushCompress, FlushDecompress, Status};
+use crate::logging::*;
+
+const CHUNK_SIZE: usize = 1024;
+
+/*
+ * zlib stream used for MCCP2 (server -> client).  Every write is sync
+ * flushed so the client can decompress it as soon as it arrives.
+ */
+#[derive(Debug)]
+pub struct OutputCompressor {
+    compress: Compress,
+}
+
+impl OutputCompressor {
+    pub fn new() -> Self {
+        OutputCompressor {
+            compress: Compress::new(Compression::default(), true),
+        }
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        self.run(data, FlushCompress::Sync)
+    }
+
+    // End the zlib stream.  The client goes back to uncompressed data after this.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.run(&[], FlushCompress::Finish)
+    }
+
+    fn run(&mut self, data: &[u8], flush: FlushCompress) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::with_capacity(data.len() + CHUNK_SIZE);
+        let mut input = data;
+
+        loop {
+            if output.capacity() - output.len() < CHUNK_SIZE {
+                output.reserve(CHUNK_SIZE);
+            }
+
+            let before = self.compress.total_in();
+            let before_len = output.len();
+            let result = self.compress.compress_vec(input, &mut output, flush);
+            let consumed = (self.compress.total_in() - before) as usize;
+            input = &input[consumed..];
+
+            match result {
+                Ok(Status::StreamEnd) => break,
+                Ok(_) => {
+                    // Output not filled means zlib has nothing more to give us
+                    if (input.is_empty() && output.len() < output.capacity())
+                        || (consumed == 0 && output.len() == before_len) {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    log_error(&format!("Compression error: {:?}", e));
+                    break;
+                },
+            }
+        }
+
+        output
+    }
+}
+
+
+/*
+ * zlib stream used for MCCP3 (client -> server).  When the client ends its
+ * stream, anything after the end is uncompressed again and gets handed back.
+ */
+#[derive(Debug)]
+pub struct InputDecompressor {
+    decompress: Decompress,
+}
+
+impl InputDecompressor {
+    pub fn new() -> Self {
+        InputDecompressor {
+            decompress: Decompress::new(true),
+        }
+    }
+
+    // Returns the decompressed data, and the uncompressed remainder if the stream ended.
+    pub fn decompress(&mut self, data: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), DecompressError> {
+        let mut output: Vec<u8> = Vec::with_capacity(data.len() * 4 + CHUNK_SIZE);
+        let mut input = data;
+
+        loop {
+            if output.capacity() - output.len() < CHUNK_SIZE {
+                output.reserve(CHUNK_SIZE * 4);
+            }
+
+            let before = self.decompress.total_in();
+            let before_len = output.len();
+            let status = self.decompress.decompress_vec(input, &mut output, FlushDecompress::Sync)?;
+            let consumed = (self.decompress.total_in() - before) as usize;
+            input = &input[consumed..];
+
+            match status {
+                Status::StreamEnd => return Ok((output, Some(input.to_vec()))),
+                _ => {
+                    if (input.is_empty() && output.len() < output.capacity())
+                        || (consumed == 0 && output.len() == before_len) {
+                        break;
+                    }
+                },
+            }
+        }
+
+        Ok((output, None))
+    }
+}
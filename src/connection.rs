@@ -1,15 +1,30 @@
 extern crate tokio;
 
-use crate::server::NetworkMessage;
+use crate::server::{wizlock_message, NetworkMessage, Server, ShutdownRequest, StreamControl, Wizlock};
+use crate::ControlSignal;
+use crate::compress::InputDecompressor;
+use crate::gmcp::{GmcpMessage, GmcpState};
+use crate::msdp::{MsdpState, MsdpValue};
+use crate::mssp;
+use crate::charset::*;
+use crate::lineassembler::{LineAssembler, LineEnding};
+use crate::ratelimit::TokenBucket;
+use crate::settings::{FloodAction, Idle, IdleProbe, Input, Listener, Protocol, Settings};
 use crate::logging::*;
 use crate::ansicolors::AnsiColors;
+use crate::bans::{check_ban, BanEntry, BanType};
+use crate::copyover::SavedConnection;
+use crate::control::{targeted, TARGET_LOGGING, TARGET_SERVER};
 use crate::dnslookup::resolve_ip;
+use crate::telnet::*;
 use tokio::sync::{broadcast, mpsc};
 use tokio::task::JoinHandle;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use minijinja::{Environment, context};
+use serde_derive::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
 #[allow(unused)]
@@ -17,6 +32,152 @@ pub struct UserMessage {
     bytes: Vec<u8>,
     string: String,
     jinja: Option<HashMap<String, String>>,
+    gmcp: Option<GmcpMessage>,
+}
+
+pub const DEFAULT_TERMINAL_WIDTH: u16 = 80;
+pub const DEFAULT_TERMINAL_HEIGHT: u16 = 24;
+
+// Number of times we'll ask the client for another terminal type
+const MAX_TTYPE_REQUESTS: usize = 4;
+
+// Don't nag a flooding player more often than this
+const FLOOD_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+/*
+ * MUD Terminal Type Standard flags, sent by the client as "MTTS <bitfield>"
+ * on the third TTYPE request.
+ */
+pub const MTTS_ANSI: u32 = 1;
+pub const MTTS_VT100: u32 = 2;
+pub const MTTS_UTF8: u32 = 4;
+pub const MTTS_256_COLORS: u32 = 8;
+pub const MTTS_MOUSE_TRACKING: u32 = 16;
+pub const MTTS_OSC_COLOR_PALETTE: u32 = 32;
+pub const MTTS_SCREEN_READER: u32 = 64;
+pub const MTTS_PROXY: u32 = 128;
+pub const MTTS_TRUECOLOR: u32 = 256;
+pub const MTTS_MNES: u32 = 512;
+pub const MTTS_MSLP: u32 = 1024;
+pub const MTTS_SSL: u32 = 2048;
+
+pub fn mtts_flag_names(mtts: u32) -> Vec<&'static str> {
+    let flags = [
+        (MTTS_ANSI, "ANSI"),
+        (MTTS_VT100, "VT100"),
+        (MTTS_UTF8, "UTF-8"),
+        (MTTS_256_COLORS, "256 COLORS"),
+        (MTTS_MOUSE_TRACKING, "MOUSE TRACKING"),
+        (MTTS_OSC_COLOR_PALETTE, "OSC COLOR PALETTE"),
+        (MTTS_SCREEN_READER, "SCREEN READER"),
+        (MTTS_PROXY, "PROXY"),
+        (MTTS_TRUECOLOR, "TRUECOLOR"),
+        (MTTS_MNES, "MNES"),
+        (MTTS_MSLP, "MSLP"),
+        (MTTS_SSL, "SSL"),
+    ];
+
+    flags.iter().filter(|(flag, _)| mtts & flag != 0).map(|(_, name)| *name).collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TerminalInfo {
+    pub width: u16,
+    pub height: u16,
+    pub client_name: Option<String>,
+    pub terminal_type: Option<String>,
+    pub ttype_names: Vec<String>,
+    pub ttype_done: bool,
+    pub mtts: u32,
+    pub ansi: bool,
+    pub utf8: bool,
+    pub colors_256: bool,
+    pub truecolor: bool,
+    pub screen_reader: bool,
+    pub encoding: Encoding,
+    pub charset_negotiated: bool,
+    pub line_ending: LineEnding,
+}
+
+impl Default for TerminalInfo {
+    fn default() -> Self {
+        TerminalInfo {
+            width: DEFAULT_TERMINAL_WIDTH,
+            height: DEFAULT_TERMINAL_HEIGHT,
+            client_name: None,
+            terminal_type: None,
+            ttype_names: vec![],
+            ttype_done: false,
+            mtts: 0,
+            ansi: false,
+            utf8: false,
+            colors_256: false,
+            truecolor: false,
+            screen_reader: false,
+            encoding: Encoding::Utf8,
+            charset_negotiated: false,
+            line_ending: LineEnding::CrLf,
+        }
+    }
+}
+
+impl TerminalInfo {
+    /*
+     * Work out what the client can render.  The MTTS bitfield is definitive
+     * when we get one, otherwise we guess from the terminal type names.
+     */
+    fn apply_capabilities(&mut self) {
+        if self.mtts != 0 {
+            self.ansi = self.mtts & MTTS_ANSI != 0;
+            self.utf8 = self.mtts & MTTS_UTF8 != 0;
+            self.colors_256 = self.mtts & MTTS_256_COLORS != 0;
+            self.truecolor = self.mtts & MTTS_TRUECOLOR != 0;
+            self.screen_reader = self.mtts & MTTS_SCREEN_READER != 0;
+            self.apply_encoding();
+            return;
+        }
+
+        for name in &self.ttype_names {
+            let upper = name.to_uppercase();
+            if ["ANSI", "XTERM", "LINUX", "SCREEN", "TMUX", "RXVT", "PUTTY", "MUDLET",
+                "MUSHCLIENT", "CMUD", "ZMUD", "TINTIN"].iter().any(|t| upper.contains(t)) {
+                self.ansi = true;
+            }
+            if upper.contains("256COLOR") {
+                self.ansi = true;
+                self.colors_256 = true;
+            }
+            if upper.contains("TRUECOLOR") {
+                self.ansi = true;
+                self.colors_256 = true;
+                self.truecolor = true;
+            }
+            if upper.contains("UTF-8") || upper.contains("UTF8") {
+                self.utf8 = true;
+            }
+        }
+        self.apply_encoding();
+    }
+
+    // A charset agreed with CHARSET always wins over what TTYPE suggested
+    fn apply_encoding(&mut self) {
+        if self.charset_negotiated {
+            return;
+        }
+
+        if self.utf8 {
+            self.encoding = Encoding::Utf8;
+        } else if self.mtts != 0 {
+            self.encoding = Encoding::Ascii;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(unused)]
+pub enum ConnectionState {
+    Login,
+    Playing,
 }
 
 #[derive(Debug, Clone)]
@@ -24,7 +185,6 @@ pub struct UserMessage {
 pub struct Connection {
     txqueue: mpsc::Sender<NetworkMessage>,
     addr: SocketAddr,
-    ansi_mode: bool,
     ansi_colors: Arc<RwLock<AnsiColors>>,
     rx_process_handle: Arc<RwLock<Option<JoinHandle<()>>>>,
     tx_process_handle: Arc<RwLock<Option<JoinHandle<()>>>>,
@@ -32,17 +192,33 @@ pub struct Connection {
     pub rxsender: Option<mpsc::Sender<NetworkMessage>>,
     pub usertxsender: Option<broadcast::Sender<UserMessage>>,
     pub userrxsender: Option<broadcast::Sender<UserMessage>>,
-    hostnames: Option<Vec<String>>,
+    hostnames: Arc<RwLock<Option<Vec<String>>>>,
+    telnet: Arc<RwLock<TelnetOptions>>,
+    terminal: Arc<RwLock<TerminalInfo>>,
+    gmcp: Arc<RwLock<GmcpState>>,
+    msdp: Arc<RwLock<MsdpState>>,
+    state: Arc<RwLock<ConnectionState>>,
+    input_limits: Input,
+    idle_limits: Idle,
+    immortal: Arc<RwLock<bool>>,
+    last_input: Arc<RwLock<Instant>>,
+    last_probe: Arc<RwLock<Instant>>,
+    idle_warned: Arc<RwLock<bool>>,
+    site_ban: Arc<RwLock<Option<BanEntry>>>,
+    ctlsender: broadcast::Sender<ControlSignal>,
+    listener: Listener,
+    character: Arc<RwLock<Option<String>>>,
+    input_compressed: Arc<RwLock<bool>>,
 }
 
 impl Connection {
-    pub async fn new(txsender: &mpsc::Sender<NetworkMessage>, addr: SocketAddr) -> Self {
+    pub async fn new(txsender: &mpsc::Sender<NetworkMessage>, addr: SocketAddr, settings: &Settings,
+                     ctlsender: &broadcast::Sender<ControlSignal>, listener: &Listener) -> Self {
         log_info(&format!("New connection from {:?}", addr));
 
-        let s = Connection {
+        let mut s = Connection {
             txqueue: txsender.clone(),
             addr: addr.clone(),
-            ansi_mode: true,
             ansi_colors: AnsiColors::get(),
             rx_process_handle: Arc::new(RwLock::new(None)),
             tx_process_handle: Arc::new(RwLock::new(None)),
@@ -50,14 +226,62 @@ impl Connection {
             rxsender: None,
             usertxsender: None,
             userrxsender: None,
-            hostnames: None,
+            hostnames: Arc::new(RwLock::new(None)),
+            telnet: Arc::new(RwLock::new(TelnetOptions::new(&[TELOPT_ECHO, TELOPT_SGA, TELOPT_EOR, TELOPT_MCCP2, TELOPT_MCCP3,
+                                                                  TELOPT_GMCP, TELOPT_MSDP, TELOPT_MSSP, TELOPT_CHARSET],
+                                                                &[TELOPT_TTYPE, TELOPT_NAWS]))),
+            terminal: Arc::new(RwLock::new(Default::default())),
+            gmcp: Arc::new(RwLock::new(Default::default())),
+            msdp: Arc::new(RwLock::new(Default::default())),
+            state: Arc::new(RwLock::new(ConnectionState::Login)),
+            input_limits: settings.input.clone(),
+            idle_limits: settings.idle.clone(),
+            immortal: Arc::new(RwLock::new(false)),
+            last_input: Arc::new(RwLock::new(Instant::now())),
+            last_probe: Arc::new(RwLock::new(Instant::now())),
+            idle_warned: Arc::new(RwLock::new(false)),
+            site_ban: Arc::new(RwLock::new(check_ban(addr.ip(), &[]))),
+            ctlsender: ctlsender.clone(),
+            listener: listener.clone(),
+            character: Arc::new(RwLock::new(None)),
+            input_compressed: Arc::new(RwLock::new(false)),
         };
 
+        // Only immortals can get in on an immortal port, so treat them as such from the start
+        s.set_immortal(listener.immortal_only);
         return s;
     }
 
     #[allow(unused)]
     pub async fn start_processing(&mut self) {
+        self.start_threads();
+        self.start_negotiation().await;
+
+        let ip_addr = self.addr.ip();
+        let dnshandle  = tokio::spawn(async move {
+            resolve_ip(ip_addr).await
+        });
+        let dnsresult = dnshandle.await;
+        let hostnames = dnsresult.unwrap_or(None);
+        *self.hostnames.write().unwrap() = hostnames.clone();
+        log_info(&format!("DNS for {:?}: {:?} (client: {:?})", ip_addr, hostnames,
+                          self.terminal.read().unwrap().client_name));
+
+        // Now that we know the hostnames, check for a ban on them as well
+        let ban = check_ban(ip_addr, &hostnames.unwrap_or(vec![]));
+        *self.site_ban.write().unwrap() = ban.clone();
+        if let Some(ban) = ban {
+            log_info(&format!("{:?} ban on {} matches {:?} ({:?}): {}", ban.ban_type, ban.pattern, self.addr,
+                              self.get_hostnames(), ban.reason));
+        }
+
+        if let Some(reason) = self.connect_refused() {
+            self.disconnect(reason).await;
+        }
+    }
+
+    #[allow(unused)]
+    fn start_threads(&mut self) {
         let (rxsender, mut rxreceiver) = mpsc::channel::<NetworkMessage>(256);
         self.rxsender = Some(rxsender);
 
@@ -79,16 +303,222 @@ impl Connection {
             txconnection.do_tx_process_thread(txsender.clone(), usertxsender.clone()).await; 
         });
         self.tx_process_handle = Arc::new(RwLock::new(Some(txhandle)));
+    }
 
-        let ip_addr = self.addr.ip().clone();
-        let dnshandle  = tokio::spawn(async move {
-            resolve_ip(ip_addr).await
-        });
-        let dnsresult = dnshandle.await;
-        self.hostnames = dnsresult.unwrap_or(None);
-        log_info(&format!("DNS for {:?}: {:?}", ip_addr, self.hostnames));
+    /*
+     * Pick up a connection carried over from the old process by a copyover.
+     * The client already agreed to its telnet options, so there's no
+     * negotiation, but MCCP2 needs a fresh stream as the old one was ended
+     * before the exec.
+     */
+    pub async fn resume_processing(&mut self, saved: SavedConnection) {
+        self.telnet.write().unwrap().restore(&saved.local_options, &saved.remote_options);
+        *self.terminal.write().unwrap() = saved.terminal;
+        *self.gmcp.write().unwrap() = saved.gmcp;
+        *self.msdp.write().unwrap() = saved.msdp;
+        *self.state.write().unwrap() = saved.state;
+        *self.character.write().unwrap() = saved.character;
+        *self.immortal.write().unwrap() = saved.immortal;
+        *self.site_ban.write().unwrap() = check_ban(self.addr.ip(), saved.hostnames.as_deref().unwrap_or(&[]));
+        *self.hostnames.write().unwrap() = saved.hostnames;
+
+        self.start_threads();
+
+        let txqueue = &self.txqueue.clone();
+        if self.local_option_enabled(TELOPT_MCCP2) {
+            self.send_control(txqueue, &subnegotiation(TELOPT_MCCP2, &[]), StreamControl::StartCompression).await;
+        }
+        self.send_line(txqueue, "The world shimmers back into focus around you.".to_string()).await;
+    }
+
+    #[allow(unused)]
+    pub fn get_site_ban(&self) -> Option<BanEntry> {
+        self.site_ban.read().unwrap().clone()
+    }
+
+    // For the login code: whether this site may log in the given character
+    #[allow(unused)]
+    pub fn site_allows_login(&self, immortal: bool) -> bool {
+        match self.get_site_ban() {
+            Some(ban) if ban.ban_type == BanType::Total => false,
+            Some(ban) if ban.ban_type == BanType::ImmortalOnly => immortal,
+            _ => true,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn site_allows_new_characters(&self) -> bool {
+        self.get_site_ban().is_none()
+    }
+
+    /*
+     * Checked when the connection is made, before anyone has logged in.  Only
+     * a total ban or the wrong port turns them away this early; immortal-only
+     * bans and wizlock have to wait for enter_game() to know who is logging in.
+     */
+    pub fn connect_refused(&self) -> Option<String> {
+        if let Some(ban) = self.get_site_ban().filter(|ban| ban.ban_type == BanType::Total) {
+            return Some(format!("Your site has been banned: {}", ban.reason));
+        }
+
+        // Until the login code says otherwise, anyone on an ordinary port is a mortal
+        if self.listener.immortal_only && !self.is_immortal() {
+            return Some("This port is for immortals only.".to_string());
+        }
+
+        None
     }
 
+    /*
+     * Checked by enter_game() once the login code knows who is logging in.
+     * Returns what to tell them if they can't: their site is banned, the
+     * port is for immortals, or the game is wizlocked.
+     */
+    pub async fn login_refused(&self, immortal: bool) -> Option<String> {
+        if !self.site_allows_login(immortal) {
+            return Some(format!("Your site has been banned: {}", self.get_site_ban().unwrap().reason));
+        }
+
+        if self.listener.immortal_only && !immortal {
+            return Some("This port is for immortals only.".to_string());
+        }
+
+        let status = Server::get_status().await;
+        if status.wizlocked && !immortal {
+            log_info(&format!("Refused mortal login from {:?} due to wizlock", self.addr));
+            return Some(wizlock_message(&status.wizlock_reason));
+        }
+
+        None
+    }
+
+    // Immortal command to lock or unlock the game, kicking mortals after a grace period if given
+    #[allow(unused)]
+    pub fn set_wizlock(&self, locked: bool, reason: &str, grace: Option<u64>) {
+        log_info(&format!("Wizlock {} from {:?}: {}", if locked { "on" } else { "off" }, self.addr, reason));
+        let wizlock = Wizlock {
+            locked,
+            reason: reason.to_string(),
+            grace,
+        };
+        let _ = self.ctlsender.send(ControlSignal::Wizlock(wizlock));
+    }
+
+    // Immortal command to shut down or reboot after a countdown, or the configured one if None
+    #[allow(unused)]
+    pub fn request_shutdown(&self, reason: &str, countdown: Option<u64>, reboot: bool) {
+        let by = self.get_character().unwrap_or(format!("{:?}", self.addr));
+        log_info(&format!("{} requested by {}: {}", if reboot { "Reboot" } else { "Shutdown" }, by, reason));
+        let request = ShutdownRequest {
+            reason: reason.to_string(),
+            countdown,
+            reboot,
+        };
+        let _ = self.ctlsender.send(ControlSignal::BeginShutdown(request));
+    }
+
+    // Immortal command to send a message to everyone
+    #[allow(unused)]
+    pub fn broadcast(&self, message: &str) {
+        let _ = self.ctlsender.send(ControlSignal::Broadcast(message.to_string()));
+    }
+
+    // Immortal command to pick up changes made to the ban list on disk
+    #[allow(unused)]
+    pub fn reload_bans(&self) {
+        log_info(&format!("Ban list reload requested from {:?}", self.addr));
+        let _ = self.ctlsender.send(targeted(TARGET_SERVER, ControlSignal::ReloadBans));
+    }
+
+    // Immortal command to change how much gets logged, until the next SIGHUP
+    #[allow(unused)]
+    pub fn set_log_level(&self, level: log::Level) {
+        log_info(&format!("Log level set to {} from {:?}", level, self.addr));
+        let _ = self.ctlsender.send(targeted(TARGET_LOGGING, ControlSignal::LogLevel(level)));
+    }
+
+    // Immortal command to reboot into a new binary without dropping anyone
+    #[allow(unused)]
+    pub fn request_copyover(&self) {
+        let by = self.get_character().unwrap_or(format!("{:?}", self.addr));
+        log_info(&format!("Copyover requested by {}", by));
+        let _ = self.ctlsender.send(ControlSignal::Copyover(by));
+    }
+
+    /*
+     * Whether this connection can be carried over.  TLS and WebSocket
+     * connections can't, and neither can a client compressing its input, as
+     * there is no way to hand over the state of the zlib stream.
+     */
+    pub fn can_copyover(&self) -> bool {
+        self.listener.protocol == Protocol::Telnet && !*self.input_compressed.read().unwrap()
+    }
+
+    // Warn the player, and end MCCP2 cleanly so the new process can start a new stream
+    pub async fn prepare_copyover(&mut self) {
+        let txqueue = &self.txqueue.clone();
+        self.send_line(txqueue, "The world shimmers and fades around you...".to_string()).await;
+        if self.local_option_enabled(TELOPT_MCCP2) {
+            self.queue_data(txqueue, &[], StreamControl::EndCompression).await;
+        }
+    }
+
+    // The exec didn't happen, so carry on as we were
+    pub async fn copyover_failed(&mut self) {
+        let txqueue = &self.txqueue.clone();
+        if self.local_option_enabled(TELOPT_MCCP2) {
+            self.send_control(txqueue, &subnegotiation(TELOPT_MCCP2, &[]), StreamControl::StartCompression).await;
+        }
+        self.send_line(txqueue, "The world steadies again.  The reboot failed.".to_string()).await;
+    }
+
+    pub fn save_state(&self, fd: i32) -> SavedConnection {
+        let (local_options, remote_options) = self.telnet.read().unwrap().enabled_options();
+        SavedConnection {
+            fd,
+            addr: self.addr,
+            listener: self.listener.clone(),
+            hostnames: self.get_hostnames(),
+            state: self.get_state(),
+            character: self.get_character(),
+            immortal: self.is_immortal(),
+            terminal: self.get_terminal_info(),
+            local_options,
+            remote_options,
+            gmcp: self.get_gmcp_state(),
+            msdp: self.get_msdp_state(),
+        }
+    }
+
+    // Whether this came in on a TLS listener
+    #[allow(unused)]
+    pub fn is_secure(&self) -> bool {
+        self.listener.protocol == Protocol::Tls
+    }
+
+    // Whether this came in on a builders' port
+    #[allow(unused)]
+    pub fn is_builder_port(&self) -> bool {
+        self.listener.builder
+    }
+
+    #[allow(unused)]
+    pub fn get_hostnames(&self) -> Option<Vec<String>> {
+        self.hostnames.read().unwrap().clone()
+    }
+
+
+    async fn start_negotiation(&mut self) {
+        self.request_local_option(TELOPT_EOR, true).await;
+        self.request_remote_option(TELOPT_TTYPE, true).await;
+        self.request_remote_option(TELOPT_NAWS, true).await;
+        self.request_local_option(TELOPT_MCCP2, true).await;
+        self.request_local_option(TELOPT_MCCP3, true).await;
+        self.request_local_option(TELOPT_GMCP, true).await;
+        self.request_local_option(TELOPT_MSDP, true).await;
+        self.request_local_option(TELOPT_MSSP, true).await;
+        self.request_local_option(TELOPT_CHARSET, true).await;
+    }
 
     async fn do_tx_process_thread(&mut self, txsender: mpsc::Sender<NetworkMessage>,
                                   usertxsender: broadcast::Sender<UserMessage>) {
@@ -97,16 +527,18 @@ impl Connection {
 
         loop {
             let msg = usertxreceiver.recv().await.unwrap();
-            if msg.string.len() == 0 && msg.bytes.len() == 0 && msg.jinja.is_none() {
+            if msg.string.is_empty() && msg.bytes.is_empty() && msg.jinja.is_none() && msg.gmcp.is_none() {
                 // diconnect user
                 break;
             }
 
-            if !msg.jinja.is_none() {
-                let jinja_str: String = self.jinja_process(msg.jinja.unwrap());
+            if let Some(gmcp) = msg.gmcp {
+                self.send_gmcp(&gmcp.package, gmcp.data).await;
+            } else if let Some(jinja) = msg.jinja {
+                let jinja_str: String = self.jinja_process(jinja);
                 self.send_string(&txsender, jinja_str).await;
-            } else if msg.bytes.len() != 0 {
-                self.send_raw(&txsender, &msg.bytes).await;
+            } else if !msg.bytes.is_empty() {
+                self.send_bytes(&txsender, &msg.bytes).await;
             } else {
                 self.send_string(&txsender, msg.string).await;
             }
@@ -131,11 +563,19 @@ impl Connection {
 
     async fn do_rx_process_thread(&mut self, mut rxreceiver: mpsc::Receiver<NetworkMessage>,
                                   userrxsender: broadcast::Sender<UserMessage>) {
-        let mut incoming_buffer: Vec<u8> = Vec::new();
+        let mut assembler = LineAssembler::new();
+        let mut lines: Vec<Vec<u8>> = vec![];
+        let mut decoder = TelnetDecoder::new();
+        let mut inflater: Option<InputDecompressor> = None;
+        let mut line_bucket = TokenBucket::new(self.input_limits.max_lines_per_second, self.input_limits.line_burst);
+        let mut last_warning: Option<Instant> = None;
+        let max_line_length = self.input_limits.max_line_length;
+
+        assembler.set_limit(self.input_limits.max_buffered_bytes);
 
         log_info(&format!("Starting Rx Process Thread for {:?}", self.addr));
 
-        while let Some(msg) = rxreceiver.recv().await {
+        'receive: while let Some(msg) = rxreceiver.recv().await {
             if msg.data.len() == 0 {
                 self.disconnect("".to_string()).await;
                 break;
@@ -143,30 +583,90 @@ impl Connection {
 
             log_info(&format!("Received {} bytes from {:?}", msg.data.len(), self.addr));
             log_debug(&format!("Data: {:?}", msg.data));
-            let mut data = self.handle_telnet_commands(msg.data);
-            log_debug(&format!("After telnet Data: {:?}", data));
-            incoming_buffer.append(&mut data);
-
-            loop {
-                let (mut linebuf, new_buffer) = self.read_line(incoming_buffer);
-                incoming_buffer = new_buffer;
-                
-                if linebuf.len() == 0 {
-                    break;
+
+            let mut pending = Some(msg.data);
+            while let Some(mut data) = pending.take() {
+                if let Some(decompressor) = inflater.as_mut() {
+                    // MCCP3: client data is compressed, and must be inflated before telnet decoding
+                    match decompressor.decompress(&data) {
+                        Ok((mut output, remainder)) => {
+                            if let Some(mut remainder) = remainder {
+                                log_info(&format!("Client ended compression for {:?}", self.addr));
+                                inflater = None;
+                                *self.input_compressed.write().unwrap() = false;
+                                output.append(&mut remainder);
+                            }
+                            data = output;
+                        },
+                        Err(e) => {
+                            log_error(&format!("Decompression error from {:?}: {:?}", self.addr, e));
+                            self.disconnect("Compression error".to_string()).await;
+                            break 'receive;
+                        },
+                    }
                 }
 
-                // send the line to the user channel
-                linebuf.pop();     // strip \r
-                linebuf.pop();     // strip \n
+                let (text, remainder) = self.handle_telnet_commands(&mut decoder, data).await;
+                log_debug(&format!("After telnet Data: {:?}", text));
+                lines.append(&mut assembler.push(&text));
+
+                if remainder.is_some() {
+                    // Everything after IAC SB MCCP3 IAC SE is compressed
+                    if self.local_option_enabled(TELOPT_MCCP3) {
+                        log_info(&format!("Client started compression for {:?}", self.addr));
+                        inflater = Some(InputDecompressor::new());
+                        *self.input_compressed.write().unwrap() = true;
+                    }
+                    pending = remainder;
+                }
+            }
+
+            if let Some(line_ending) = assembler.line_ending() {
+                if line_ending != self.get_line_ending() {
+                    log_debug(&format!("Line ending for {:?}: {:?}", self.addr, line_ending));
+                    self.terminal.write().unwrap().line_ending = line_ending;
+                }
+            }
+
+            if assembler.take_overflow()
+                && !self.input_limit_exceeded("Too much input without a newline", &mut last_warning).await {
+                break 'receive;
+            }
+
+            for mut linebuf in lines.drain(..) {
                 log_debug(&format!("Line Buffer: {:?}", linebuf));
 
-                let line: String = String::from_utf8_lossy(&linebuf).to_string();
+                if !line_bucket.take() {
+                    if !self.input_limit_exceeded("Commands sent too quickly", &mut last_warning).await {
+                        break 'receive;
+                    }
+                    continue;
+                }
+
+                if max_line_length != 0 && linebuf.len() > max_line_length {
+                    if !self.input_limit_exceeded("Input line too long", &mut last_warning).await {
+                        break 'receive;
+                    }
+                    linebuf.truncate(max_line_length);
+                }
+
+                self.reset_idle();
+
+                let line: String = self.get_encoding().decode(&linebuf);
                 log_debug(&line);
 
+                if self.get_state() == ConnectionState::Login && line.trim() == mssp::MSSP_REQUEST {
+                    let txqueue = &self.txqueue.clone();
+                    let reply = mssp::encode_text(&mssp::mssp_variables().await);
+                    self.send_bytes(txqueue, reply.as_bytes()).await;
+                    continue;
+                }
+
                 let usermsg = UserMessage {
                     bytes: linebuf.clone(),
                     string: line,
                     jinja: None,
+                    gmcp: None,
                 };
                 let _ = userrxsender.send(usermsg);
             }
@@ -174,46 +674,339 @@ impl Connection {
         log_info(&format!("Shutting down Rx Process Thread for {:?}", self.addr));
     }
 
+    /*
+     * Deal with a client going over one of the input limits, according to
+     * the configured action.  Excess input is always dropped.  Returns false
+     * if the client was disconnected for it.
+     */
+    async fn input_limit_exceeded(&mut self, problem: &str, last_warning: &mut Option<Instant>) -> bool {
+        let action = self.input_limits.flood_action;
+        log_info(&format!("Input limit exceeded by {:?} ({:?}): {} ({:?})", self.addr, self.get_hostnames(),
+                          problem, action));
+
+        let txqueue = &self.txqueue.clone();
+        match action {
+            FloodAction::Truncate => true,
+            FloodAction::Warn => {
+                let nag = last_warning.map(|t| t.elapsed() >= FLOOD_WARNING_INTERVAL).unwrap_or(true);
+                if nag {
+                    self.send_line(txqueue, format!("*** {}, ignoring the excess ***", problem)).await;
+                    *last_warning = Some(Instant::now());
+                }
+                true
+            },
+            FloodAction::Disconnect => {
+                self.disconnect(format!("{}, disconnecting.", problem)).await;
+                false
+            },
+        }
+    }
+
+    fn reset_idle(&mut self) {
+        *self.last_input.write().unwrap() = Instant::now();
+        *self.idle_warned.write().unwrap() = false;
+    }
+
+    #[allow(unused)]
+    pub fn get_idle_time(&self) -> Duration {
+        self.last_input.read().unwrap().elapsed()
+    }
+
+    fn idle_timeout(&self) -> u64 {
+        match self.get_state() {
+            ConnectionState::Login => self.idle_limits.login_timeout,
+            ConnectionState::Playing if self.is_immortal() => 0,
+            ConnectionState::Playing => self.idle_limits.playing_timeout,
+        }
+    }
+
+    /*
+     * Called regularly by the server thread.  Players idle for too long get
+     * a warning, and then get disconnected.  Quiet connections are probed
+     * with a telnet command, so a dead socket gives an error on the write.
+     */
+    pub async fn check_idle(&mut self) {
+        if self.disconnected {
+            return;
+        }
+
+        let txqueue = &self.txqueue.clone();
+        let idle = self.get_idle_time().as_secs();
+        let timeout = self.idle_timeout();
+
+        if timeout != 0 {
+            if idle >= timeout {
+                log_info(&format!("Idle timeout for {:?} ({:?}) after {} seconds", self.addr, self.get_hostnames(), idle));
+                self.disconnect("You have been idle too long, disconnecting.".to_string()).await;
+                return;
+            }
+
+            let warning_time = self.idle_limits.warning_time;
+            let warned = *self.idle_warned.read().unwrap();
+            if warning_time != 0 && idle + warning_time >= timeout && !warned {
+                *self.idle_warned.write().unwrap() = true;
+                self.send_line(txqueue, format!("*** You are idle, and will be disconnected in {} seconds ***",
+                                                timeout - idle)).await;
+            }
+        }
+
+        let probe_interval = self.idle_limits.probe_interval;
+        let since_probe = self.last_probe.read().unwrap().elapsed().as_secs();
+        if probe_interval != 0 && idle >= probe_interval && since_probe >= probe_interval {
+            *self.last_probe.write().unwrap() = Instant::now();
+            match self.idle_limits.probe {
+                IdleProbe::Off => {},
+                IdleProbe::Nop => self.send_telnet(txqueue, &command(NOP)).await,
+                IdleProbe::Ayt => self.send_telnet(txqueue, &command(AYT)).await,
+            }
+        }
+    }
+
     pub async fn disconnect(&mut self, reason: String) {
         let txqueue = &self.txqueue.clone();
         self.send_line(txqueue, reason).await;
-        self.send_raw(txqueue, b"").await;
+        self.queue_data(txqueue, b"", StreamControl::None).await;
         self.disconnected = true;
     }
 
-    pub async fn send_raw(&mut self, txqueue: &mpsc::Sender<NetworkMessage>, message: &[u8]) {
+    // Text data.  Any 0xFF is doubled so the client doesn't see it as IAC.
+    pub async fn send_bytes(&mut self, txqueue: &mpsc::Sender<NetworkMessage>, message: &[u8]) {
+        if message.is_empty() {
+            return;
+        }
+        self.queue_data(txqueue, &escape_iac(message), StreamControl::None).await;
+    }
+
+    // Telnet commands go out exactly as built
+    pub async fn send_telnet(&mut self, txqueue: &mpsc::Sender<NetworkMessage>, command: &TelnetCommand) {
+        self.queue_data(txqueue, command.as_bytes(), StreamControl::None).await;
+    }
+
+    // Send a telnet command, then have the server change the state of the output stream
+    pub async fn send_control(&mut self, txqueue: &mpsc::Sender<NetworkMessage>, command: &TelnetCommand,
+                              control: StreamControl) {
+        self.queue_data(txqueue, command.as_bytes(), control).await;
+    }
+
+    async fn queue_data(&mut self, txqueue: &mpsc::Sender<NetworkMessage>, message: &[u8],
+                        control: StreamControl) {
         let mut msgvec = vec![];
         msgvec.extend_from_slice(message);
         let outmsg = NetworkMessage {
-            dest: self.addr.clone(),
+            dest: self.addr,
             data: msgvec,
+            control,
         };
         let _ = txqueue.send(outmsg).await;
     }
 
     #[allow(unused)]
     pub async fn set_echo(&mut self, enable: bool) {
-        let txqueue = &self.txqueue.clone();
+        // WILL ECHO means we echo, so the client stops echoing locally
+        self.request_local_option(TELOPT_ECHO, enable).await;
+    }
 
-        if enable {
-            // IAC, WILL, TELOPT_ECHO
-            self.send_raw(txqueue, &[0xFF, 0xFB, 0x01]).await;
-        } else {
-            // IAC, WONT, TELOPT_ECHO
-            self.send_raw(txqueue, &[0xFF, 0xFC, 0x01]).await;
+    #[allow(unused)]
+    pub fn local_option_enabled(&self, option: u8) -> bool {
+        self.telnet.read().unwrap().local_enabled(option)
+    }
+
+    #[allow(unused)]
+    pub fn remote_option_enabled(&self, option: u8) -> bool {
+        self.telnet.read().unwrap().remote_enabled(option)
+    }
+
+    #[allow(unused)]
+    pub fn get_option_state(&self, option: u8) -> OptionState {
+        self.telnet.read().unwrap().get(option)
+    }
+
+    #[allow(unused)]
+    pub async fn request_local_option(&mut self, option: u8, enable: bool) {
+        let (response, change) = {
+            self.telnet.write().unwrap().request_local(option, enable)
+        };
+        self.finish_negotiation(response, change).await;
+    }
+
+    #[allow(unused)]
+    pub async fn request_remote_option(&mut self, option: u8, enable: bool) {
+        let (response, change) = {
+            self.telnet.write().unwrap().request_remote(option, enable)
+        };
+        self.finish_negotiation(response, change).await;
+    }
+
+    async fn finish_negotiation(&mut self, response: Option<TelnetCommand>, change: Option<OptionChange>) {
+        if let Some(response) = response {
+            let txqueue = &self.txqueue.clone();
+            self.send_telnet(txqueue, &response).await;
         }
+
+        if let Some(change) = change {
+            self.handle_option_change(change).await;
+        }
+    }
+
+    async fn handle_option_change(&mut self, change: OptionChange) {
+        log_debug(&format!("Telnet option change for {:?}: {:?}", self.addr, change));
+
+        match change {
+            OptionChange::RemoteEnabled(TELOPT_TTYPE) => {
+                self.request_terminal_type().await;
+            },
+            OptionChange::LocalEnabled(TELOPT_MCCP2) => {
+                // MCCP2: compression starts immediately after IAC SB MCCP2 IAC SE
+                let txqueue = &self.txqueue.clone();
+                self.send_control(txqueue, &subnegotiation(TELOPT_MCCP2, &[]),
+                                  StreamControl::StartCompression).await;
+            },
+            OptionChange::LocalDisabled(TELOPT_MCCP2) => {
+                let txqueue = &self.txqueue.clone();
+                self.queue_data(txqueue, &[], StreamControl::EndCompression).await;
+            },
+            OptionChange::LocalEnabled(TELOPT_CHARSET) => {
+                let txqueue = &self.txqueue.clone();
+                self.send_telnet(txqueue, &charset_request()).await;
+            },
+            OptionChange::LocalEnabled(TELOPT_MSSP) => {
+                let txqueue = &self.txqueue.clone();
+                let reply = mssp::encode_telnet(&mssp::mssp_variables().await);
+                self.send_telnet(txqueue, &reply).await;
+            },
+            OptionChange::RemoteDisabled(TELOPT_NAWS) => {
+                // Client won't tell us, so go back to assuming the defaults
+                let mut terminal = self.terminal.write().unwrap();
+                terminal.width = DEFAULT_TERMINAL_WIDTH;
+                terminal.height = DEFAULT_TERMINAL_HEIGHT;
+            },
+            _ => {},
+        }
+    }
+
+    pub fn get_state(&self) -> ConnectionState {
+        *self.state.read().unwrap()
+    }
+
+    #[allow(unused)]
+    pub fn set_state(&mut self, state: ConnectionState) {
+        *self.state.write().unwrap() = state;
+    }
+
+    /*
+     * For the login code, once a character has logged in on this connection.
+     * From here on the connection gets the playing idle timeout, or none at
+     * all for an immortal.  Returns false if they were turned away instead.
+     */
+    #[allow(unused)]
+    pub async fn enter_game(&mut self, character: &str, immortal: bool) -> bool {
+        if let Some(reason) = self.login_refused(immortal).await {
+            log_info(&format!("Refused login for {} from {:?}: {}", character, self.addr, reason));
+            self.disconnect(reason).await;
+            return false;
+        }
+
+        log_info(&format!("{} entered the game from {:?}{}", character, self.addr,
+                          if immortal { " (immortal)" } else { "" }));
+        self.set_character(Some(character.to_string()));
+        self.set_immortal(immortal);
+        self.set_state(ConnectionState::Playing);
+        self.reset_idle();
+        true
+    }
+
+    pub fn is_immortal(&self) -> bool {
+        *self.immortal.read().unwrap()
+    }
+
+    // The character logged in on this connection, set by the login code
+    #[allow(unused)]
+    pub fn get_character(&self) -> Option<String> {
+        self.character.read().unwrap().clone()
+    }
+
+    #[allow(unused)]
+    pub fn set_character(&mut self, name: Option<String>) {
+        *self.character.write().unwrap() = name;
+    }
+
+    // Immortals are never disconnected for being idle
+    #[allow(unused)]
+    pub fn set_immortal(&mut self, immortal: bool) {
+        *self.immortal.write().unwrap() = immortal;
+    }
+
+    #[allow(unused)]
+    pub fn get_terminal_info(&self) -> TerminalInfo {
+        self.terminal.read().unwrap().clone()
+    }
+
+    #[allow(unused)]
+    pub fn ansi_mode(&self) -> bool {
+        self.terminal.read().unwrap().ansi
+    }
+
+    #[allow(unused)]
+    pub fn set_ansi_mode(&mut self, enable: bool) {
+        self.terminal.write().unwrap().ansi = enable;
+    }
+
+    #[allow(unused)]
+    pub fn get_window_size(&self) -> (u16, u16) {
+        let terminal = self.terminal.read().unwrap();
+        (terminal.width, terminal.height)
+    }
+
+    #[allow(unused)]
+    pub fn get_terminal_width(&self) -> u16 {
+        self.terminal.read().unwrap().width
+    }
+
+    #[allow(unused)]
+    pub fn get_terminal_height(&self) -> u16 {
+        self.terminal.read().unwrap().height
     }
 
     #[allow(unused)]
     pub async fn send_string(&mut self, txqueue: &mpsc::Sender<NetworkMessage>, message: String) {
         let mut ansi_colors = self.ansi_colors.clone();
-        let ansimsg = ansi_colors.read().unwrap().convert_string(message, self.ansi_mode);
-        self.send_raw(txqueue, &ansimsg).await;
+        let ansimsg = ansi_colors.read().unwrap().convert_string(message, self.ansi_mode());
+        let encoded = self.get_encoding().encode(&String::from_utf8_lossy(&ansimsg));
+        self.send_bytes(txqueue, &encoded).await;
+    }
+
+    // Send a message word-wrapped to the width of the player's window
+    #[allow(unused)]
+    pub async fn send_wrapped(&mut self, txqueue: &mpsc::Sender<NetworkMessage>, message: String) {
+        let width = self.get_terminal_width() as usize;
+        let line_ending = self.get_line_ending();
+        self.send_string(txqueue, word_wrap(&message, width, line_ending.as_str())).await;
+    }
+
+    /*
+     * A prompt has no newline, so the client needs to be told where it ends.
+     * That is IAC EOR if the client agreed to it, otherwise IAC GA, unless
+     * go-ahead has been suppressed.
+     */
+    #[allow(unused)]
+    pub async fn send_prompt(&mut self, txqueue: &mpsc::Sender<NetworkMessage>, message: String) {
+        self.send_string(txqueue, message).await;
+
+        if self.local_option_enabled(TELOPT_EOR) {
+            self.send_telnet(txqueue, &command(EOR)).await;
+        } else if !self.local_option_enabled(TELOPT_SGA) {
+            self.send_telnet(txqueue, &command(GA)).await;
+        }
     }
 
     #[allow(unused)]
     pub async fn send_line(&mut self, txqueue: &mpsc::Sender<NetworkMessage>, message: String) {
-        self.send_string(txqueue, message + "\r\n").await;
+        let line_ending = self.get_line_ending();
+        self.send_string(txqueue, message + line_ending.as_str()).await;
+    }
+
+    pub fn get_line_ending(&self) -> LineEnding {
+        self.terminal.read().unwrap().line_ending
     }
 
     /*
@@ -236,95 +1029,334 @@ impl Connection {
      * 0xFF 0xFE 0xXX is "IAC DONT option"
      * 0xFF 0xFF      is "IAC IAC" - send 0xFF
      */
-    fn handle_telnet_commands(&mut self, data: Vec<u8>) -> Vec<u8> {
-        let mut b: Vec<u8> = data.clone();
-            
-        loop {
-            let mut iter = b.iter();
-            let pos = iter.position(|&x| x == b'\xFF');
-            if pos.is_none() {
-                break;
+    async fn handle_telnet_commands(&mut self, decoder: &mut TelnetDecoder, data: Vec<u8>) -> (Vec<u8>, Option<Vec<u8>>) {
+        let mut b: Vec<u8> = vec![];
+        let (events, remainder) = decoder.decode(&data);
+
+        for event in events {
+            match event {
+                TelnetEvent::Data(mut text) => b.append(&mut text),
+                TelnetEvent::Command(cmd) => self.handle_command(cmd).await,
+                TelnetEvent::Negotiation(cmd, option) => {
+                    log_debug(&format!("Received IAC {} {} from {:?}", command_name(cmd), option, self.addr));
+                    let (response, change) = {
+                        self.telnet.write().unwrap().receive(cmd, option)
+                    };
+                    self.finish_negotiation(response, change).await;
+                },
+                TelnetEvent::Subnegotiation(option, payload) => {
+                    self.handle_subnegotiation(option, payload).await;
+                },
             }
+        }
 
-            let i = pos.unwrap();
+        (b, remainder)
+    }
 
-            let command = iter.next();
-            if command.is_none() {
-                break;
-            }
+    async fn handle_command(&mut self, cmd: u8) {
+        log_debug(&format!("Received IAC {} from {:?}", command_name(cmd), self.addr));
 
-            let cmd: u8 = *command.unwrap();
-            if cmd == b'\xFF' {
-                //  We want to just leave the one 0xFF
-                b.drain(i..i + 1);
-            } else if cmd >= b'\xFB' {
-                // These commands have a following option
-                b.drain(i..i + 3);
-            } else {
-                b.drain(i..i + 2);
-            }
+        if cmd == AYT {
+            let txqueue = &self.txqueue.clone();
+            self.send_string(txqueue, "[Yes]\r\n".to_string()).await;
         }
+    }
+
+    async fn handle_subnegotiation(&mut self, option: u8, payload: Vec<u8>) {
+        log_debug(&format!("Received subnegotiation for option {} from {:?}: {:?}", option, self.addr, payload));
 
-        return b;
+        match option {
+            TELOPT_NAWS => self.handle_naws(payload),
+            TELOPT_TTYPE => self.handle_ttype(payload).await,
+            TELOPT_GMCP => self.handle_gmcp(payload),
+            TELOPT_CHARSET => self.handle_charset(payload).await,
+            TELOPT_MSDP => self.handle_msdp(payload).await,
+            TELOPT_MCCP3 => {
+                // Start of client compression, handled in the Rx thread
+            },
+            _ => {
+                log_debug(&format!("Ignoring subnegotiation for unsupported option {}", option));
+            },
+        }
     }
 
-    fn read_line(&mut self, buffer: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
-        let mut buf: Vec<u8> = buffer.clone();
-        let s: Vec<u8> = buffer.clone();
-        loop {
-            // If there is a CRLF in the text, we have a full line to return to the caller.
-            let mut i = 0;
-            let mut found = false;
-            let mut iter = s.iter().peekable();
-
-            loop {
-                let pos = iter.position(|&x| x == b'\r');
-                if pos.is_none() {
-                    break;
-                }
+    /*
+     * NAWS (RFC1073): IAC SB NAWS <width16> <height16> IAC SE
+     * A value of 0 means the client doesn't know that dimension.
+     */
+    fn handle_naws(&mut self, payload: Vec<u8>) {
+        if payload.len() != 4 {
+            log_debug(&format!("Bad NAWS payload from {:?}: {:?}", self.addr, payload));
+            return;
+        }
 
-                let nextvalue = iter.peek();
-                if nextvalue.is_none() {
-                    break;
-                }
+        let width = u16::from_be_bytes([payload[0], payload[1]]);
+        let height = u16::from_be_bytes([payload[2], payload[3]]);
+
+        let mut terminal = self.terminal.write().unwrap();
+        terminal.width = if width == 0 { DEFAULT_TERMINAL_WIDTH } else { width };
+        terminal.height = if height == 0 { DEFAULT_TERMINAL_HEIGHT } else { height };
+        log_info(&format!("Window size for {:?}: {}x{}", self.addr, terminal.width, terminal.height));
+    }
+
+    /*
+     * Core.Hello and Core.Supports.* are tracked here, everything else goes
+     * to the user channel for the game to deal with.
+     */
+    fn handle_gmcp(&mut self, payload: Vec<u8>) {
+        let message = GmcpMessage::parse(&payload);
+        if message.is_none() {
+            log_debug(&format!("Bad GMCP payload from {:?}: {:?}", self.addr, payload));
+            return;
+        }
+
+        let message = message.unwrap();
+        log_debug(&format!("GMCP from {:?}: {:?}", self.addr, message));
+
+        let handled = {
+            self.gmcp.write().unwrap().handle_core(&message)
+        };
+        if handled {
+            return;
+        }
+
+        if let Some(userrxsender) = &self.userrxsender {
+            let usermsg = UserMessage {
+                bytes: vec![],
+                string: "".to_string(),
+                jinja: None,
+                gmcp: Some(message),
+            };
+            let _ = userrxsender.send(usermsg);
+        }
+    }
+
+    #[allow(unused)]
+    pub fn get_gmcp_state(&self) -> GmcpState {
+        self.gmcp.read().unwrap().clone()
+    }
+
+    #[allow(unused)]
+    pub fn gmcp_supported(&self, package: &str) -> bool {
+        self.local_option_enabled(TELOPT_GMCP) && self.gmcp.read().unwrap().is_supported(package)
+    }
+
+    // Only sent if GMCP is on, and the client asked for this package
+    #[allow(unused)]
+    pub async fn send_gmcp(&mut self, package: &str, data: serde_json::Value) {
+        if !self.gmcp_supported(package) {
+            return;
+        }
+
+        let txqueue = &self.txqueue.clone();
+        let message = GmcpMessage::new(package, data);
+        self.send_telnet(txqueue, &message.encode()).await;
+    }
+
+    #[allow(unused)]
+    pub fn get_encoding(&self) -> Encoding {
+        self.terminal.read().unwrap().encoding
+    }
 
-                if nextvalue.unwrap() == &&b'\n' {
-                    i = pos.unwrap();
-                    found = true;
-                    break;
-                } else if iter.next().is_none() {
-                    break;
+    #[allow(unused)]
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        let mut terminal = self.terminal.write().unwrap();
+        terminal.encoding = encoding;
+        terminal.charset_negotiated = true;
+    }
+
+    /*
+     * CHARSET (RFC2066): we send a REQUEST listing what we support, and the
+     * client answers ACCEPTED or REJECTED.  The client may also send its own
+     * REQUEST, which we answer the same way.
+     */
+    async fn handle_charset(&mut self, payload: Vec<u8>) {
+        if payload.is_empty() {
+            return;
+        }
+
+        match payload[0] {
+            CHARSET_ACCEPTED => {
+                let name = String::from_utf8_lossy(&payload[1..]).to_string();
+                match Encoding::from_name(&name) {
+                    Some(encoding) => {
+                        self.set_encoding(encoding);
+                        log_info(&format!("Charset for {:?}: {}", self.addr, name));
+                    },
+                    None => log_info(&format!("Client {:?} accepted unknown charset {}", self.addr, name)),
                 }
+            },
+            CHARSET_REJECTED => {
+                log_info(&format!("Client {:?} rejected our charsets, using {}", self.addr,
+                                  self.get_encoding().name()));
+            },
+            CHARSET_REQUEST => {
+                let txqueue = &self.txqueue.clone();
+                if let Some((encoding, name)) = choose_encoding(&payload[1..]) {
+                    let mut response = vec![CHARSET_ACCEPTED];
+                    response.extend_from_slice(name.as_bytes());
+                    self.send_telnet(txqueue, &subnegotiation(TELOPT_CHARSET, &response)).await;
+                    self.set_encoding(encoding);
+                    log_info(&format!("Charset for {:?}: {}", self.addr, name));
+                } else {
+                    self.send_telnet(txqueue, &subnegotiation(TELOPT_CHARSET, &[CHARSET_REJECTED])).await;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    async fn handle_msdp(&mut self, payload: Vec<u8>) {
+        if !self.local_option_enabled(TELOPT_MSDP) {
+            return;
+        }
+
+        let responses = {
+            self.msdp.write().unwrap().handle_payload(&payload)
+        };
+
+        let txqueue = &self.txqueue.clone();
+        for response in responses {
+            self.send_telnet(txqueue, &response).await;
+        }
+    }
+
+    #[allow(unused)]
+    pub fn get_msdp_state(&self) -> MsdpState {
+        self.msdp.read().unwrap().clone()
+    }
+
+    // Publish a game value to this player, sent if they asked to have it reported
+    #[allow(unused)]
+    pub async fn update_msdp_variable(&mut self, name: &str, value: MsdpValue) {
+        let message = {
+            self.msdp.write().unwrap().update(name, value)
+        };
+
+        if let Some(message) = message {
+            if self.local_option_enabled(TELOPT_MSDP) {
+                let txqueue = &self.txqueue.clone();
+                self.send_telnet(txqueue, &message).await;
             }
+        }
+    }
+
+    async fn request_terminal_type(&mut self) {
+        let txqueue = &self.txqueue.clone();
+        self.send_telnet(txqueue, &subnegotiation(TELOPT_TTYPE, &[TTYPE_SEND])).await;
+    }
 
-            if !found {
-                return (vec![], buf);
+    /*
+     * TTYPE (RFC1091) with MTTS: each IAC SB TTYPE SEND IAC SE gets the next
+     * name from the client.  The first is the client name, the second the
+     * terminal type, and the third "MTTS <flags>".  When the client runs out
+     * of names it repeats the last one.
+     */
+    async fn handle_ttype(&mut self, payload: Vec<u8>) {
+        if payload.first() != Some(&TTYPE_IS) {
+            log_debug(&format!("Bad TTYPE payload from {:?}: {:?}", self.addr, payload));
+            return;
+        }
+
+        let name = String::from_utf8_lossy(&payload[1..]).trim().to_string();
+        let ask_again = {
+            let mut terminal = self.terminal.write().unwrap();
+            if terminal.ttype_done {
+                return;
             }
 
-            let (line_data, new_buf) = s.split_at(i + 2);
-            let mut line = line_data.to_vec();
-            buf = new_buf.to_vec();
-            
-            // Process any backspaces
-            loop {
-                let s2 = line.clone();
-                let mut iter2 = s2.iter();
-        
-                let pos = iter2.position(|&x| x == b'\x08');
-                if pos.is_none() {
-                    break;
+            // Every reply is kept, MTTS ones too, so a client that repeats
+            // itself or never says anything useful still ends the cycle
+            let repeated = terminal.ttype_names.last() == Some(&name);
+            if !repeated {
+                if let Some(flags) = name.strip_prefix("MTTS ") {
+                    terminal.mtts = flags.trim().parse().unwrap_or(0);
+                } else if terminal.client_name.is_none() {
+                    terminal.client_name = Some(name.clone());
+                } else if terminal.terminal_type.is_none() {
+                    terminal.terminal_type = Some(name.clone());
                 }
-                
-                let i = pos.unwrap();
-                if i == 0 {
-                    line.drain(..1);
+                terminal.ttype_names.push(name.clone());
+            }
+
+            let done = repeated || terminal.mtts != 0 || terminal.ttype_names.len() >= MAX_TTYPE_REQUESTS;
+            if done {
+                terminal.ttype_done = true;
+                terminal.apply_capabilities();
+            }
+            !done
+        };
+
+        if ask_again {
+            self.request_terminal_type().await;
+            return;
+        }
+
+        let terminal = self.get_terminal_info();
+        log_info(&format!("Client for {:?} ({:?}): {:?}, terminal {:?}, MTTS {:?}, ANSI {}, UTF-8 {}",
+                          self.addr, self.get_hostnames(), terminal.client_name, terminal.terminal_type,
+                          mtts_flag_names(terminal.mtts), terminal.ansi, terminal.utf8));
+    }
+}
+
+
+/*
+ * Wrap text at the given width, breaking on spaces.  Color codes ($cXXXX)
+ * take up no room on the screen, so they are not counted in the width.
+ * Existing line breaks are kept, and new ones use the given line ending.
+ */
+pub fn word_wrap(message: &str, width: usize, eol: &str) -> String {
+    if width == 0 {
+        return message.to_string();
+    }
+
+    let mut output = String::new();
+    let mut first_line = true;
+
+    for line in message.split('\n') {
+        if !first_line {
+            output.push('\n');
+        }
+        first_line = false;
+
+        let mut column = 0;
+        let mut first_word = true;
+        for word in line.split(' ') {
+            let length = visible_length(word);
+            if !first_word {
+                if column + 1 + length > width {
+                    output.push_str(eol);
+                    column = 0;
                 } else {
-                    line.drain(i - 1..i + 1);
+                    output.push(' ');
+                    column += 1;
                 }
             }
-
-            return (line, buf);
+            first_word = false;
+            output.push_str(word);
+            column += length;
         }
     }
+
+    output
 }
 
+fn visible_length(word: &str) -> usize {
+    let chars: Vec<char> = word.chars().collect();
+    let mut length = 0;
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] == '$' && i + 5 < chars.len() && (chars[i + 1] == 'c' || chars[i + 1] == 'C')
+            && chars[i + 2..i + 5].iter().all(|c| c.is_ascii_digit()) && !chars[i + 5].is_whitespace() {
+            i += 6;
+        } else if chars[i] == '\r' {
+            i += 1;
+        } else {
+            length += 1;
+            i += 1;
+        }
+    }
+
+    length
+}
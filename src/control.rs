@@ -0,0 +1,176 @@
+extern crate tokio;
+
+use crate::logging::*;
+use crate::ControlSignal;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, oneshot};
+
+// How long to wait for the answer to a request on the control bus
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Saving everything can take a while, but shouldn't hold up a shutdown forever
+const SAVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Control bus names of the subsystems, for messages meant for just one of them
+pub const TARGET_MAIN: &str = "main";
+pub const TARGET_LOGGING: &str = "logging";
+pub const TARGET_SIGNALS: &str = "signals";
+pub const TARGET_DNS: &str = "dns";
+pub const TARGET_SERVER: &str = "server";
+
+/*
+ * Questions one part of the server can ask another over the control bus,
+ * and their answers.  Sent to a single subsystem with request().
+ */
+#[derive(Debug, Clone)]
+pub enum Request {
+    ConnectionCount,
+    // Each connection, and the character logged in on it
+    Connections,
+    // Save all characters and the world, answered once it's done
+    SaveAll,
+}
+
+#[derive(Debug, Clone)]
+pub enum Response {
+    ConnectionCount(usize),
+    Connections(Vec<(SocketAddr, Option<String>)>),
+    Saved,
+    // What couldn't be saved, and why
+    SaveFailed(Vec<String>),
+}
+
+/*
+ * Where the answer to a request goes.  Signals on the bus get cloned for
+ * every receiver, so the channel is shared, and only the first answer is
+ * passed on.
+ */
+#[derive(Debug, Clone)]
+pub struct Responder {
+    sender: Arc<Mutex<Option<oneshot::Sender<Response>>>>,
+}
+
+impl Responder {
+    pub fn respond(&self, response: Response) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(response);
+        }
+    }
+}
+
+// Wrap a signal so only the named subsystem acts on it
+pub fn targeted(target: &str, signal: ControlSignal) -> ControlSignal {
+    ControlSignal::To(target.to_string(), Box::new(signal))
+}
+
+// Ask a subsystem something and wait for the answer
+#[allow(unused)]
+pub async fn request(ctlsender: &broadcast::Sender<ControlSignal>, target: &str, request: Request) -> Result<Response, String> {
+    request_within(ctlsender, target, request, REQUEST_TIMEOUT).await
+}
+
+async fn request_within(ctlsender: &broadcast::Sender<ControlSignal>, target: &str, request: Request,
+                        timeout: Duration) -> Result<Response, String> {
+    let (sender, receiver) = oneshot::channel::<Response>();
+    let responder = Responder {
+        sender: Arc::new(Mutex::new(Some(sender))),
+    };
+
+    ctlsender.send(targeted(target, ControlSignal::Request(request.clone(), responder)))
+        .map_err(|e| e.to_string())?;
+
+    match tokio::time::timeout(timeout, receiver).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(_)) => Err(format!("{} didn't answer {:?}", target, request)),
+        Err(_) => Err(format!("{} took too long to answer {:?}", target, request)),
+    }
+}
+
+/*
+ * Anything with state to write out before a shutdown registers a hook here,
+ * once at startup.  Hooks are run in the order they were registered, from a
+ * blocking thread, and say what went wrong if they couldn't save.
+ */
+pub type SaveHook = fn() -> Result<(), String>;
+
+use lazy_static::lazy_static;
+lazy_static! {
+    static ref SAVE_HOOKS: RwLock<Vec<(String, SaveHook)>> = RwLock::new(vec![]);
+}
+
+pub fn register_save_hook(name: &str, hook: SaveHook) {
+    SAVE_HOOKS.write().unwrap().push((name.to_string(), hook));
+}
+
+// Runs every hook, even after one fails, and answers a SaveAll request
+pub fn run_save_hooks() -> Response {
+    let hooks = SAVE_HOOKS.read().unwrap().clone();
+    let mut failed = vec![];
+    for (name, hook) in hooks {
+        if let Err(e) = hook() {
+            log_error(&format!("Couldn't save {}: {}", name, e));
+            failed.push(format!("{}: {}", name, e));
+        }
+    }
+
+    if failed.is_empty() {
+        Response::Saved
+    } else {
+        Response::SaveFailed(failed)
+    }
+}
+
+/*
+ * Have everything saved, then shut down.  Nothing hears about the shutdown
+ * until the save has finished, or taken too long.
+ */
+pub async fn save_and_shutdown(ctlsender: &broadcast::Sender<ControlSignal>) {
+    match request_within(ctlsender, TARGET_MAIN, Request::SaveAll, SAVE_TIMEOUT).await {
+        Ok(Response::SaveFailed(failed)) => log_error(&format!("Shutting down with unsaved changes: {}", failed.join(", "))),
+        Ok(_) => log_info("Everything saved"),
+        Err(e) => log_error(&format!("Shutting down without saving: {}", e)),
+    }
+    let _ = ctlsender.send(ControlSignal::Shutdown);
+}
+
+
+/*
+ * A subsystem's end of the control bus.  Passes on everything broadcast,
+ * and anything sent to this subsystem by name, but not what's meant for
+ * the others.  A receiver that falls behind loses the oldest signals, which
+ * gets logged rather than taking the subsystem down.
+ */
+pub struct ControlReceiver {
+    name: String,
+    receiver: broadcast::Receiver<ControlSignal>,
+}
+
+impl ControlReceiver {
+    pub fn new(name: &str, ctlsender: &broadcast::Sender<ControlSignal>) -> Self {
+        ControlReceiver {
+            name: name.to_string(),
+            receiver: ctlsender.subscribe(),
+        }
+    }
+
+    // None once the bus is gone
+    pub async fn recv(&mut self) -> Option<ControlSignal> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(ControlSignal::To(target, signal)) => {
+                    if target == self.name {
+                        return Some(*signal);
+                    }
+                },
+                Ok(signal) => return Some(signal),
+                Err(RecvError::Lagged(missed)) => {
+                    log_error(&format!("Control bus receiver for {} fell behind, missed {} signals", self.name, missed));
+                },
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::connection::{ConnectionState, TerminalInfo};
+use crate::gmcp::GmcpState;
+use crate::msdp::MsdpState;
+use crate::settings::Listener;
+use serde_derive::{Deserialize, Serialize};
+use socket2::SockRef;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::os::fd::{BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+use tokio::net::TcpStream;
+
+/*
+ * Hot reboot.  Each player's socket is left open across an exec of the new
+ * binary, and what we know about the connection is written out to a file
+ * for the new process to pick up.  It is started with "--copyover <file>",
+ * and puts the connections back together from the file and the inherited
+ * file descriptors.
+ *
+ * Only plain telnet connections can be carried over.  TLS sessions and the
+ * WebSocket gateway have state in this process that can't be handed on.
+ */
+pub const COPYOVER_ARG: &str = "--copyover";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedConnection {
+    pub fd: RawFd,
+    pub addr: SocketAddr,
+    pub listener: Listener,
+    pub hostnames: Option<Vec<String>>,
+    pub state: ConnectionState,
+    pub character: Option<String>,
+    pub immortal: bool,
+    pub terminal: TerminalInfo,
+    pub local_options: Vec<u8>,
+    pub remote_options: Vec<u8>,
+    pub gmcp: GmcpState,
+    pub msdp: MsdpState,
+}
+
+use lazy_static::lazy_static;
+lazy_static! {
+    static ref RECOVERY_FILE: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
+}
+
+// Called from main with the file named on the command line
+pub fn set_recovery_file(path: Option<String>) {
+    *RECOVERY_FILE.write().unwrap() = path;
+}
+
+// The server thread picks this up once it has started listening
+pub fn take_recovery_file() -> Option<String> {
+    RECOVERY_FILE.write().unwrap().take()
+}
+
+// "--copyover <file>" from the command line, if we were started by a copyover
+pub fn recovery_file_from_args() -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+    let index = args.iter().position(|arg| arg == COPYOVER_ARG)?;
+    args.get(index + 1).cloned()
+}
+
+pub fn save(data_dir: &str, connections: &[SavedConnection]) -> Result<String, String> {
+    let path = String::from(Path::new(data_dir).join("copyover.json").to_str().unwrap());
+    let text = serde_json::to_string(connections).map_err(|e| e.to_string())?;
+    fs::write(&path, text).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(path)
+}
+
+/*
+ * Reads the saved connections, and removes the file so they can't be used
+ * twice.  If the file doesn't make sense, the sockets it names are closed,
+ * rather than left open with nobody reading them.
+ */
+pub fn load(path: &str) -> Result<Vec<SavedConnection>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let result = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e));
+    if result.is_err() {
+        close_listed(&text);
+    }
+    let _ = fs::remove_file(path);
+    result
+}
+
+// Picks the "fd" fields out of a file that wouldn't load, even a cut off one
+fn close_listed(text: &str) {
+    for field in text.split("\"fd\":").skip(1) {
+        let digits: String = field.trim_start().chars().take_while(|c| c.is_ascii_digit()).collect();
+        let Ok(fd) = digits.parse::<RawFd>() else {
+            continue;
+        };
+
+        // Anything that isn't an inherited socket is left alone
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        if fd > 2 && SockRef::from(&borrowed).r#type().is_ok() {
+            drop(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+    }
+}
+
+// Sockets are opened close-on-exec, so each one to be kept has to be marked
+pub fn keep_open(fd: RawFd) -> Result<(), String> {
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    SockRef::from(&fd).set_cloexec(false).map_err(|e| e.to_string())
+}
+
+/*
+ * Start the new binary in place of this process, with the same arguments
+ * and the file of saved connections.  Only returns if the exec failed.
+ */
+pub fn exec(path: &str) -> String {
+    // By name rather than current_exe(), which still points at the old binary once it's replaced
+    let mut original = env::args();
+    let program = original.next().unwrap_or_default();
+
+    // Drop any copyover file we were started with ourselves
+    let mut args: Vec<String> = vec![];
+    let mut skip = false;
+    for arg in original {
+        if skip {
+            skip = false;
+        } else if arg == COPYOVER_ARG {
+            skip = true;
+        } else {
+            args.push(arg);
+        }
+    }
+
+    let error = Command::new(program).args(args).arg(COPYOVER_ARG).arg(path).exec();
+    error.to_string()
+}
+
+// Take over an inherited socket in the new process
+pub fn restore_stream(fd: RawFd) -> std::io::Result<TcpStream> {
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    SockRef::from(&stream).set_cloexec(true)?;
+    stream.set_nonblocking(true)?;
+    TcpStream::from_std(stream)
+}
@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+// Seconds left at which the players get told again
+const ANNOUNCE_AT: [u64; 11] = [600, 300, 120, 60, 30, 15, 10, 5, 3, 2, 1];
+
+/*
+ * Counts down to something happening to the players, such as being kicked
+ * for wizlock, saying how long is left now and then along the way.
+ */
+#[derive(Debug, Clone)]
+pub struct Countdown {
+    deadline: Instant,
+    announced: Option<u64>,
+}
+
+impl Countdown {
+    pub fn new(seconds: u64) -> Self {
+        Countdown {
+            deadline: Instant::now() + Duration::from_secs(seconds),
+            announced: None,
+        }
+    }
+
+    pub fn remaining(&self) -> u64 {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        left.as_secs() + if left.subsec_nanos() != 0 { 1 } else { 0 }
+    }
+
+    pub fn is_done(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    // The seconds left, if it is time to tell everyone
+    pub fn announcement(&mut self) -> Option<u64> {
+        let remaining = self.remaining();
+        let due = match self.announced {
+            None => true,
+            Some(last) => ANNOUNCE_AT.iter().any(|&at| remaining <= at && last > at),
+        };
+
+        if !due || remaining == 0 {
+            return None;
+        }
+
+        self.announced = Some(remaining);
+        Some(remaining)
+    }
+}
+
+// "2 minutes", "1 minute 30 seconds", "10 seconds"
+pub fn describe_seconds(seconds: u64) -> String {
+    let plural = |n: u64, unit: &str| if n == 1 { format!("1 {}", unit) } else { format!("{} {}s", n, unit) };
+    let (minutes, seconds) = (seconds / 60, seconds % 60);
+
+    if minutes == 0 {
+        plural(seconds, "second")
+    } else if seconds == 0 {
+        plural(minutes, "minute")
+    } else {
+        format!("{} {}", plural(minutes, "minute"), plural(seconds, "second"))
+    }
+}
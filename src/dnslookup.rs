@@ -1,12 +1,12 @@
 extern crate tokio;
 
 use std::net::*;
-use tokio::sync::{broadcast, mpsc, Barrier, Mutex};
+use tokio::sync::{broadcast, mpsc, Mutex};
 use hickory_resolver::TokioAsyncResolver;
 use hickory_resolver::config::*;
 use crate::logging::*;
 use std::sync::Arc;
-use crate::ControlSignal;
+use crate::supervisor::TaskContext;
 use tokio::time::{timeout, Duration};
 
 
@@ -33,12 +33,10 @@ lazy_static! {
 }
 
 #[allow(unused)]
-pub async fn do_dns_lookup_thread(barrier: Arc<Barrier>, shutdown_barrier: Arc<Barrier>,
-                                  ctlsender: broadcast::Sender<ControlSignal>) {
+pub async fn do_dns_lookup_thread(mut ctx: TaskContext) {
     log_info("Starting DNS Lookup Thread");
 
     let mut shutdown = false;
-    let mut ctlqueue = ctlsender.subscribe();
     let (request_sender, mut request_receiver) = mpsc::channel::<DnsItem>(256);
     let (response_sender, _response_receiver) = broadcast::channel::<DnsItem>(256);
 
@@ -50,17 +48,12 @@ pub async fn do_dns_lookup_thread(barrier: Arc<Barrier>, shutdown_barrier: Arc<B
 
     let resolver = Arc::new(TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default()));
 
-    let _ = barrier.wait().await;
+    ctx.ready();
 
     while !shutdown {
         tokio::select! {
-            v = ctlqueue.recv() => {
-                match v.unwrap() {
-                    ControlSignal::Shutdown => {
-                        shutdown = true;
-                    },
-                    ControlSignal::Reconfigure(_) => {},
-                };
+            _ = ctx.stopping() => {
+                shutdown = true;
             }, 
             v = request_receiver.recv() => {
                 let mut item = v.unwrap().clone();
@@ -75,7 +68,6 @@ pub async fn do_dns_lookup_thread(barrier: Arc<Barrier>, shutdown_barrier: Arc<B
     }
 
     log_info("Shutting down DNS Lookup Thread");
-    let _ = shutdown_barrier.wait().await;
 }
 
 async fn reverse_lookup(resolver: Arc<TokioAsyncResolver>, response_sender: broadcast::Sender<DnsItem>, addr: IpAddr) {
@@ -0,0 +1,131 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use crate::telnet::*;
+
+/*
+ * Generic MUD Communication Protocol.  Each subnegotiation carries a package
+ * name, optionally followed by a space and a JSON payload:
+ *     IAC SB GMCP "Package.SubPackage.Message <json>" IAC SE
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct GmcpMessage {
+    pub package: String,
+    pub data: Value,
+}
+
+impl GmcpMessage {
+    pub fn new(package: &str, data: Value) -> Self {
+        GmcpMessage {
+            package: package.to_string(),
+            data,
+        }
+    }
+
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let text = String::from_utf8_lossy(payload).to_string();
+        let text = text.trim();
+        if text.is_empty() {
+            return None;
+        }
+
+        let (package, json) = match text.find(|c: char| c.is_whitespace()) {
+            Some(i) => (&text[..i], text[i..].trim()),
+            None => (text, ""),
+        };
+
+        let data = if json.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_str(json).ok()?
+        };
+
+        Some(GmcpMessage::new(package, data))
+    }
+
+    pub fn encode(&self) -> TelnetCommand {
+        let mut payload = self.package.clone();
+        if !self.data.is_null() {
+            payload.push(' ');
+            payload.push_str(&self.data.to_string());
+        }
+        subnegotiation(TELOPT_GMCP, payload.as_bytes())
+    }
+}
+
+
+/*
+ * What the client told us about itself with Core.Hello, and which packages
+ * it asked for with Core.Supports.Set/Add/Remove.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GmcpState {
+    pub client: Option<String>,
+    pub version: Option<String>,
+    pub supports: HashMap<String, u32>,
+}
+
+impl GmcpState {
+    // Returns true if the message was a Core message we handled ourselves
+    pub fn handle_core(&mut self, message: &GmcpMessage) -> bool {
+        match message.package.to_lowercase().as_str() {
+            "core.hello" => {
+                self.client = message.data.get("client").and_then(|v| v.as_str()).map(|s| s.to_string());
+                self.version = message.data.get("version").and_then(|v| v.as_str()).map(|s| s.to_string());
+            },
+            "core.supports.set" => {
+                self.supports.clear();
+                self.add_supports(&message.data);
+            },
+            "core.supports.add" => self.add_supports(&message.data),
+            "core.supports.remove" => {
+                for (package, _) in parse_supports(&message.data) {
+                    self.supports.remove(&package);
+                }
+            },
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn add_supports(&mut self, data: &Value) {
+        for (package, version) in parse_supports(data) {
+            self.supports.insert(package, version);
+        }
+    }
+
+    /*
+     * Core is always allowed.  Otherwise the client must have asked for the
+     * package or one of its parents, i.e. "Char" covers "Char.Vitals".
+     */
+    pub fn is_supported(&self, package: &str) -> bool {
+        let lower = package.to_lowercase();
+        if lower == "core" || lower.starts_with("core.") {
+            return true;
+        }
+
+        let parts: Vec<&str> = lower.split('.').collect();
+        (1..=parts.len()).any(|i| self.supports.contains_key(&parts[..i].join(".")))
+    }
+}
+
+// Supports lists look like ["Char 1", "Room 1", "Comm.Channel 1"]
+fn parse_supports(data: &Value) -> Vec<(String, u32)> {
+    let mut packages = vec![];
+
+    if let Some(list) = data.as_array() {
+        for item in list {
+            if let Some(text) = item.as_str() {
+                let mut parts = text.split_whitespace();
+                let package = parts.next().unwrap_or("").to_lowercase();
+                let version = parts.next().and_then(|v| v.parse().ok()).unwrap_or(1);
+                if !package.is_empty() {
+                    packages.push((package, version));
+                }
+            }
+        }
+    }
+
+    packages
+}
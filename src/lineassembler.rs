@@ -0,0 +1,215 @@
+use serde_derive::{Deserialize, Serialize};
+
+/*
+ * Assembles input lines from the client, whatever it uses to end them.
+ * RFC854 says CR LF or CR NUL, but in practice we also get bare LF (netcat
+ * and friends), LF CR and bare CR.  Whichever one the client uses is
+ * remembered so we can answer in kind.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LineEnding {
+    CrLf,
+    LfCr,
+    Lf,
+    CrNul,
+    Cr,
+}
+
+impl LineEnding {
+    // What we send at the end of our lines to a client using this convention
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::CrLf | LineEnding::CrNul | LineEnding::Cr => "\r\n",
+            LineEnding::LfCr => "\n\r",
+            LineEnding::Lf => "\n",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LineAssembler {
+    buffer: Vec<u8>,
+    pending: Option<u8>,
+    detected: Option<LineEnding>,
+    limit: usize,
+    overflowed: bool,
+}
+
+impl LineAssembler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn line_ending(&self) -> Option<LineEnding> {
+        self.detected
+    }
+
+    /*
+     * Most bytes to hold while waiting for the end of a line, 0 for no limit.
+     * Anything past that is thrown away, so a client that never sends a
+     * newline can't use up all our memory.
+     */
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    // Whether anything was thrown away since the last time we asked
+    pub fn take_overflow(&mut self) -> bool {
+        let overflowed = self.overflowed;
+        self.overflowed = false;
+        overflowed
+    }
+
+    /*
+     * Feed in decoded data, getting back any complete lines without their
+     * line endings.  A line is finished as soon as we see CR or LF, so a
+     * bare CR client isn't left waiting.  The byte after it is then checked
+     * to see whether it was the second half of a two byte line ending.
+     */
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut lines = vec![];
+
+        for &byte in data {
+            if let Some(pending) = self.pending.take() {
+                let ending = match (pending, byte) {
+                    (b'\r', b'\n') => Some(LineEnding::CrLf),
+                    (b'\r', 0) => Some(LineEnding::CrNul),
+                    (b'\n', b'\r') if self.detected != Some(LineEnding::Lf) => Some(LineEnding::LfCr),
+                    _ => None,
+                };
+
+                if ending.is_some() {
+                    self.detected = ending;
+                    continue;
+                }
+
+                self.detected = if pending == b'\r' { Some(LineEnding::Cr) } else { Some(LineEnding::Lf) };
+            }
+
+            match byte {
+                b'\r' | b'\n' => {
+                    lines.push(self.buffer.drain(..).collect());
+                    self.pending = Some(byte);
+                },
+                0 => {
+                    // Stray NUL, ignore it
+                },
+                b'\x08' | b'\x7F' => {
+                    // Backspace or delete
+                    self.buffer.pop();
+                },
+                _ => {
+                    if self.limit != 0 && self.buffer.len() >= self.limit {
+                        self.overflowed = true;
+                    } else {
+                        self.buffer.push(byte);
+                    }
+                },
+            }
+        }
+
+        lines
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(assembler: &mut LineAssembler, data: &[u8]) -> Vec<String> {
+        assembler.push(data).into_iter().map(|l| String::from_utf8(l).unwrap()).collect()
+    }
+
+    #[test]
+    fn crlf() {
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"look\r\nnorth\r\n"), ["look", "north"]);
+        assert_eq!(assembler.line_ending(), Some(LineEnding::CrLf));
+    }
+
+    #[test]
+    fn lfcr() {
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"look\n\rnorth\n\r"), ["look", "north"]);
+        assert_eq!(assembler.line_ending(), Some(LineEnding::LfCr));
+    }
+
+    #[test]
+    fn bare_lf() {
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"look\nnorth\n"), ["look", "north"]);
+        assert_eq!(assembler.line_ending(), Some(LineEnding::Lf));
+    }
+
+    #[test]
+    fn cr_nul() {
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"look\r\0north\r\0"), ["look", "north"]);
+        assert_eq!(assembler.line_ending(), Some(LineEnding::CrNul));
+    }
+
+    #[test]
+    fn bare_cr() {
+        let mut assembler = LineAssembler::new();
+        // The line is given back at once, but the ending is only known from the next byte
+        assert_eq!(lines(&mut assembler, b"look\r"), ["look"]);
+        assert_eq!(assembler.line_ending(), None);
+        assert_eq!(lines(&mut assembler, b"north\r"), ["north"]);
+        assert_eq!(assembler.line_ending(), Some(LineEnding::Cr));
+    }
+
+    #[test]
+    fn empty_lines() {
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"\r\n\r\nlook\r\n"), ["", "", "look"]);
+        assert_eq!(assembler.line_ending(), Some(LineEnding::CrLf));
+
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"\n\nlook\n"), ["", "", "look"]);
+        assert_eq!(assembler.line_ending(), Some(LineEnding::Lf));
+    }
+
+    #[test]
+    fn crlf_split_across_reads() {
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"look\r"), ["look"]);
+        assert!(lines(&mut assembler, b"\n").is_empty());
+        assert_eq!(assembler.line_ending(), Some(LineEnding::CrLf));
+        assert_eq!(lines(&mut assembler, b"north\r\n"), ["north"]);
+    }
+
+    #[test]
+    fn lfcr_split_across_reads() {
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"look\n"), ["look"]);
+        assert!(lines(&mut assembler, b"\r").is_empty());
+        assert_eq!(assembler.line_ending(), Some(LineEnding::LfCr));
+    }
+
+    #[test]
+    fn cr_nul_split_across_reads() {
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"look\r"), ["look"]);
+        assert!(lines(&mut assembler, b"\0").is_empty());
+        assert_eq!(assembler.line_ending(), Some(LineEnding::CrNul));
+    }
+
+    #[test]
+    fn partial_line_waits_for_more() {
+        let mut assembler = LineAssembler::new();
+        assert!(lines(&mut assembler, b"lo").is_empty());
+        assert_eq!(lines(&mut assembler, b"ok\r\n"), ["look"]);
+    }
+
+    #[test]
+    fn backspace_and_limit() {
+        let mut assembler = LineAssembler::new();
+        assert_eq!(lines(&mut assembler, b"lookx\x08\r\n"), ["look"]);
+
+        assembler.set_limit(4);
+        assert_eq!(lines(&mut assembler, b"northwest\r\n"), ["nort"]);
+        assert!(assembler.take_overflow());
+        assert!(!assembler.take_overflow());
+    }
+}
@@ -2,8 +2,9 @@ extern crate tokio;
 
 use simplelog::*;
 use std::fs::OpenOptions;
-use tokio::sync::{broadcast, mpsc, Barrier, RwLock};
+use tokio::sync::{mpsc, RwLock};
 use std::sync::Arc;
+use crate::supervisor::TaskContext;
 use crate::ControlSignal;
 
 
@@ -107,16 +108,18 @@ impl Logging {
         self.initialized = true;
     }
     
-    pub async fn log_thread(& self, barrier: Arc<Barrier>, shutdown_barrier: Arc<Barrier>, ctltx: broadcast::Sender<ControlSignal>, 
-                            mut logrx: mpsc::Receiver<LogMessage>) {
+    pub async fn log_thread(& self, mut ctx: TaskContext, mut logrx: mpsc::Receiver<LogMessage>) {
         let mut shutdown = false;
         let mut draining = false;
         let mut drained = false;
-        let mut ctlqueue = ctltx.subscribe();
+        let mut ctlqueue = ctx.subscribe();
+
+        // We hold the logger for as long as we run, so a new level is kept here
+        let mut level = self.level;
 
         self.log_info("Starting logging thread".to_string());
 
-        let _ = barrier.wait().await;
+        ctx.ready();
 
         while (!shutdown || draining) && !drained {
             let mut a = None;
@@ -133,38 +136,39 @@ impl Logging {
                             a = Some(v.unwrap());
                         }
                     }, 
-                    v = ctlqueue.recv() => b = Some(v.unwrap()),
+                    v = ctlqueue.recv(), if !shutdown => {
+                        match v {
+                            Some(ControlSignal::LogLevel(new_level)) => level = new_level,
+                            Some(ControlSignal::Reconfigure(settings)) => {
+                                level = if settings.debug { Level::Debug } else { Level::Info };
+                            },
+                            _ => {},
+                        }
+                    },
+                    _ = ctx.stopping(), if !shutdown => b = Some(()),
                 }
             }
             
             if !a.is_none() {
                 let log_message = a.unwrap();
                 let message = log_message.message.to_owned();
-                if log_enabled!(log_message.level) && log_message.level <= self.level {
+                if log_enabled!(log_message.level) && log_message.level <= level {
                     log!(log_message.level, "{}", message);
                 }
             }
 
             if !b.is_none() {
-                match b.unwrap() {
-                    ControlSignal::Shutdown => {
-                        shutdown = true;
-                        draining = true;
-                        drained = false;
+                // Stopped last, so all other tasks have already logged their dying gasps.
+                shutdown = true;
+                draining = true;
+                drained = false;
 
-                        // Allow all other tasks to log dying gasps.
-                        shutdown_barrier.wait().await;
-
-                        info!("Draining logs");
-                        logrx.close();
-                    },
-                    ControlSignal::Reconfigure(_) => {},
-                }
+                info!("Draining logs");
+                logrx.close();
             }
         }
 
         drop(logrx);
-        drop(ctlqueue);
 
         info!("Shutting down Logging thread");
     }
@@ -211,13 +215,9 @@ impl Logging {
 }
 
 
-pub async fn do_log_thread(barrier: Arc<Barrier>, shutdown_barrier: Arc<Barrier>, ctlqueue: broadcast::Sender<ControlSignal>, 
-    logrx: mpsc::Receiver<LogMessage>) {
-
-    let _ = tokio::spawn(async move {
-        let logger = LOGGER.read().await;
-        logger.log_thread(barrier, shutdown_barrier, ctlqueue, logrx).await;
-    });
+pub async fn do_log_thread(ctx: TaskContext, logrx: mpsc::Receiver<LogMessage>) {
+    let logger = LOGGER.read().await;
+    logger.log_thread(ctx, logrx).await;
 }
 
 #[allow(unused)]
@@ -7,15 +7,32 @@ mod connection;
 mod logging;
 mod ansicolors;
 mod dnslookup;
+mod telnet;
+mod compress;
+mod gmcp;
+mod msdp;
+mod mssp;
+mod charset;
+mod lineassembler;
+mod ratelimit;
+mod bans;
+mod countdown;
+mod tls;
+mod websocket;
+mod proxy;
+mod copyover;
+mod supervisor;
+mod control;
 
-use tokio::signal;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::{broadcast, mpsc, Barrier};
+use tokio::sync::{broadcast, mpsc};
 use settings::Settings;
-use server::do_server_thread;
+use server::{do_server_thread, ShutdownRequest, Wizlock};
 use dnslookup::do_dns_lookup_thread;
+use supervisor::{RestartPolicy, Supervisor, TaskContext};
+use control::{register_save_hook, run_save_hooks, save_and_shutdown, ControlReceiver, Request, Responder, TARGET_DNS, TARGET_LOGGING, TARGET_MAIN,
+              TARGET_SERVER, TARGET_SIGNALS};
 use logging::*;
-use std::sync::Arc;
 use std::env;
 use std::process;
 
@@ -23,13 +40,31 @@ use std::process;
 #[derive(Debug, Clone)]
 pub enum ControlSignal {
     Reconfigure(Settings),
+    Wizlock(Wizlock),
+    // Who asked for it
+    Copyover(String),
+    // Count down, then shut down or reboot
+    BeginShutdown(ShutdownRequest),
+    // A message to every player
+    Broadcast(String),
+    LogLevel(log::Level),
+    // Reread the ban list from disk
+    ReloadBans,
+    // Answered by whoever it is sent to, see control::request()
+    Request(Request, Responder),
+    // Only for the named subsystem, see control::targeted()
+    To(String, Box<ControlSignal>),
     Shutdown,
 }
 
+// Lets the script that starts us tell a reboot from a shutdown
+const REBOOT_EXIT_STATUS: i32 = 2;
+
 
 #[tokio::main]
 async fn main() {
     let mut shutdown = false;
+    let mut reboot = false;
     let appname: String = String::from("HavokMudRust");
     
     let (logtx, logrx) = mpsc::channel::<LogMessage>(256);
@@ -38,6 +73,12 @@ async fn main() {
 
     log_info(&format!("Starting {}", appname));
 
+    let recovery_file = copyover::recovery_file_from_args();
+    if let Some(path) = &recovery_file {
+        log_info(&format!("Recovering from copyover with {}", path));
+    }
+    copyover::set_recovery_file(recovery_file);
+
     let mut settings = Settings::new(&appname).unwrap().clone();
     log_info(&format!("Settings: {:?}", settings));
 
@@ -51,134 +92,160 @@ async fn main() {
         env::set_var(key, profile);
     }
 
-    let (ctltx, mut ctlrx) = broadcast::channel::<ControlSignal>(4);
+    // Whatever needs writing out before a shutdown
+    register_save_hook("bans", bans::save_all_bans);
 
-    let thread_count = 6; // no barrier in Ctrl-C handler, but include the main thread
-    let barrier = Arc::new(Barrier::new(thread_count - 1));
-    let shutdown_barrier = Arc::new(Barrier::new(thread_count));
+    let (ctltx, _) = broadcast::channel::<ControlSignal>(64);
+    let mut ctlrx = ControlReceiver::new(TARGET_MAIN, &ctltx);
 
-    let mut task_handle_list = vec![]; 
+    /*
+     * Each long running task, and the ones it needs running before it can
+     * start.  They are stopped in the reverse order.
+     */
+    let mut supervisor = Supervisor::new(&ctltx);
 
-    // Start logging thread
-    let log_barrier = barrier.clone();
-    let log_shdn_barrier = shutdown_barrier.clone();
-    let log_ctltx = ctltx.clone();
-    let log_handle = tokio::spawn(async move {
-        do_log_thread(log_barrier, log_shdn_barrier, log_ctltx, logrx).await; 
-    });
-    log_info(&format!("Log Thread: {:?}", log_handle));
-    task_handle_list.push(log_handle);
-    
-    // Start Ctrl-C handler thread
-    log_info("Starting Ctrl-C Handler thread");
-    let ctrlc_ctltx = ctltx.clone();
-    let ctrlc_handle = tokio::spawn(async move {
-        signal::ctrl_c().await.unwrap();
-        ctrlc_ctltx.send(ControlSignal::Shutdown.clone()).unwrap();
-        let _ = tokio::spawn(async move {
-            signal::ctrl_c().await.unwrap();
-            process::exit(1);
-        });
+    // Never restarted, as the log queue can only be handed over once
+    let mut logrx = Some(logrx);
+    supervisor.register(TARGET_LOGGING, &[], RestartPolicy::Shutdown, move |ctx| {
+        Box::pin(do_log_thread(ctx, logrx.take().unwrap()))
     });
-    log_info(&format!("Ctrl-C Thread: {:?}", ctrlc_handle));
-    task_handle_list.push(ctrlc_handle);
-
-    // Start SIGHUP handler thread
-    let sighup_barrier = barrier.clone();
-    let sighup_shdn_barrier = shutdown_barrier.clone();
-    let sighup_ctltx = ctltx.clone();
-    let mut sighup_ctlrx = ctltx.subscribe();
-    let sighup_handle = tokio::spawn(async move {
-        log_info("Starting SIGHUP Handler thread");
-    
-        let _ = sighup_barrier.wait().await;
-
-        let mut stream = signal(SignalKind::hangup()).unwrap();
-        let mut shutdown = false;
-
-        while !shutdown {
-            tokio::select! {
-                v = sighup_ctlrx.recv() => {
-                    match v.unwrap() {
-                        ControlSignal::Shutdown => {
-                            shutdown = true;
-                        },
-                        ControlSignal::Reconfigure(_) => {},
-                    };
-                }, 
-                _ = stream.recv() => {
-                    log_info("Recieved SIGHUP, reloading config");
-                    let new_settings = Settings::new(&appname).unwrap().clone();
-                    let ctrlsignal = ControlSignal::Reconfigure(new_settings.clone());
-                    sighup_ctltx.send(ctrlsignal.clone()).unwrap_or_else(|e| panic!("Error: {:?}", e));
-                    Logging::set_debug(new_settings.debug).await;
-                },
-            }
-        }
-    
-        log_info("Shutting down SIGHUP Handler thread");
-        let _ = sighup_shdn_barrier.wait().await;
+
+    let signal_appname = appname.clone();
+    supervisor.register(TARGET_SIGNALS, &[TARGET_LOGGING], RestartPolicy::Restart, move |ctx| {
+        Box::pin(do_signal_thread(ctx, signal_appname.clone()))
     });
-    log_info(&format!("SIGHUP Thread: {:?}", sighup_handle));
-    task_handle_list.push(sighup_handle);
-
-    // Now we need to start the server thread
-    let server_barrier = barrier.clone();
-    let server_shdn_barrier = shutdown_barrier.clone();
-    let server_ctltx = ctltx.clone();
-    let server_handle = tokio::spawn(async move {
-        do_server_thread(server_barrier, server_shdn_barrier, server_ctltx).await;
+
+    supervisor.register(TARGET_DNS, &[TARGET_LOGGING], RestartPolicy::Restart, |ctx| {
+        Box::pin(do_dns_lookup_thread(ctx))
     });
-    log_info(&format!("Server Thread: {:?}", server_handle));
-    task_handle_list.push(server_handle);
-
-    // Start up the DNS Lookup thread
-    let dns_barrier = barrier.clone();
-    let dns_shdn_barrier = shutdown_barrier.clone();
-    let dns_ctltx = ctltx.clone();
-    let dns_handle = tokio::spawn(async move {
-        do_dns_lookup_thread(dns_barrier, dns_shdn_barrier, dns_ctltx).await;
+
+    supervisor.register(TARGET_SERVER, &[TARGET_LOGGING, TARGET_DNS], RestartPolicy::Shutdown, |ctx| {
+        Box::pin(do_server_thread(ctx))
     });
-    log_info(&format!("DNS Lookup Thread: {:?}", dns_handle));
-    task_handle_list.push(dns_handle);
 
-    // Now wait for all the barriers
-    let _ = barrier.wait().await;
+    let result = supervisor.start().await;
+    if result.is_err() {
+        log_error(&format!("Startup failed: {}", result.err().unwrap()));
+        supervisor.stop().await;
+        process::exit(1);
+    }
 
     // Send the settings to all threads that care.
     let ctrlsignal = ControlSignal::Reconfigure(settings.clone());
     ctltx.send(ctrlsignal.clone()).unwrap_or_else(|e| panic!("Error: {:?}", e));
 
     while !shutdown {
-        let ctlmsg = ctlrx.recv().await.unwrap();
-        match ctlmsg {
-            ControlSignal::Shutdown => {
-                shutdown = true;
-            },
-            ControlSignal::Reconfigure(new_settings) => {
-                settings = new_settings.clone();
-                log_info(&format!("New Settings: {:?}", settings));
+        tokio::select! {
+            v = ctlrx.recv() => {
+                // The bus only closes once everything else has gone
+                match v.unwrap_or(ControlSignal::Shutdown) {
+                    ControlSignal::Shutdown => {
+                        shutdown = true;
+                    },
+                    ControlSignal::Reconfigure(new_settings) => {
+                        settings = new_settings.clone();
+                        log_info(&format!("New Settings: {:?}", settings));
+                    },
+                    ControlSignal::Wizlock(wizlock) => {
+                        log_info(&format!("Wizlock: {:?}", wizlock));
+                    },
+                    ControlSignal::Copyover(by) => {
+                        log_info(&format!("Copyover by {}", by));
+                    },
+                    ControlSignal::BeginShutdown(request) => {
+                        log_info(&format!("Shutdown requested: {:?}", request));
+                        reboot = request.reboot;
+                    },
+                    ControlSignal::Request(Request::SaveAll, responder) => {
+                        log_info("Saving all characters and world state");
+                        // Writing files would hold up the control bus, so it's done on the side
+                        tokio::task::spawn_blocking(move || responder.respond(run_save_hooks()));
+                    },
+                    ControlSignal::Broadcast(message) => {
+                        log_info(&format!("Broadcast: {}", message));
+                    },
+                    ControlSignal::LogLevel(level) => {
+                        log_info(&format!("Log level: {}", level));
+                    },
+                    _ => {},
+                }
             },
+            _ = supervisor.watch() => {},
         }
     } 
 
-    // Account for any finished threads
-    for handle in &task_handle_list {
-        if handle.is_finished() {
-            info!("Already finished {:?}", handle);
-            let local_barrier = shutdown_barrier.clone();
-            let _ = local_barrier.wait().await;
-        }
+    supervisor.stop().await;
+
+    info!("Shutting down main thread");
+    if reboot {
+        process::exit(REBOOT_EXIT_STATUS);
     }
+}
 
+/*
+ * SIGHUP reloads the config.  SIGTERM and Ctrl-C start a shutdown, giving
+ * the players a countdown.  A second Ctrl-C skips the countdown, and a third
+ * gives up on shutting down cleanly.
+ */
+async fn do_signal_thread(mut ctx: TaskContext, appname: String) {
+    log_info("Starting signal handler thread");
+
+    let ctltx = ctx.ctlsender.clone();
+    let mut sighup = signal(SignalKind::hangup()).unwrap();
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    let mut interrupts = 0;
+    let mut shutdown = false;
 
-    let _ = shutdown_barrier.wait().await;
+    ctx.ready();
 
-    // Shut down after all threads are done
-    for handle in task_handle_list.drain(..) {
-        info!("Waiting for {:?}", handle);
-        if handle.await.is_err() {}
+    while !shutdown {
+        tokio::select! {
+            _ = ctx.stopping() => {
+                shutdown = true;
+            },
+            _ = sighup.recv() => {
+                log_info("Recieved SIGHUP, reloading config");
+                match Settings::new(&appname) {
+                    Ok(new_settings) => {
+                        let ctrlsignal = ControlSignal::Reconfigure(new_settings.clone());
+                        ctltx.send(ctrlsignal.clone()).unwrap_or_else(|e| panic!("Error: {:?}", e));
+                    },
+                    // A mistake in the file shouldn't take down a running server
+                    Err(e) => log_error(&format!("Couldn't reload config, keeping the old one: {}", e)),
+                }
+            },
+            _ = sigterm.recv() => {
+                log_info("Received SIGTERM, starting shutdown");
+                let _ = ctltx.send(ControlSignal::BeginShutdown(shutdown_request()));
+            },
+            _ = sigint.recv() => {
+                interrupts += 1;
+                if interrupts == 1 {
+                    log_info("Received Ctrl-C, starting shutdown");
+                    let _ = ctltx.send(ControlSignal::BeginShutdown(shutdown_request()));
+                } else if interrupts == 2 {
+                    log_info("Received Ctrl-C again, shutting down now");
+                    // Off to the side, so a third Ctrl-C still gets through during the save
+                    let sender = ctltx.clone();
+                    tokio::spawn(async move {
+                        save_and_shutdown(&sender).await;
+                    });
+                } else {
+                    process::exit(1);
+                }
+            },
+        }
     }
 
-    info!("Shutting down main thread");
+    log_info("Shutting down signal handler thread");
+}
+
+// A shutdown from a signal, with the configured countdown
+fn shutdown_request() -> ShutdownRequest {
+    ShutdownRequest {
+        reason: "".to_string(),
+        countdown: None,
+        reboot: false,
+    }
 }
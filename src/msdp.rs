@@ -0,0 +1,432 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use lazy_static::lazy_static;
+use crate::telnet::*;
+use serde_derive::{Deserialize, Serialize};
+
+/*
+ * MUD Server Data Protocol.  Variables and values are delimited by marker
+ * bytes, and values can nest as tables and arrays:
+ *     IAC SB MSDP MSDP_VAR "HEALTH" MSDP_VAL "100" IAC SE
+ */
+pub const MSDP_VAR: u8 = 1;
+pub const MSDP_VAL: u8 = 2;
+pub const MSDP_TABLE_OPEN: u8 = 3;
+pub const MSDP_TABLE_CLOSE: u8 = 4;
+pub const MSDP_ARRAY_OPEN: u8 = 5;
+pub const MSDP_ARRAY_CLOSE: u8 = 6;
+
+const COMMANDS: [&str; 5] = ["LIST", "REPORT", "RESET", "SEND", "UNREPORT"];
+const LISTS: [&str; 6] = ["COMMANDS", "LISTS", "CONFIGURABLE_VARIABLES", "REPORTABLE_VARIABLES",
+                          "REPORTED_VARIABLES", "SENDABLE_VARIABLES"];
+const CONFIGURABLE_VARIABLES: [&str; 3] = ["CLIENT_NAME", "CLIENT_VERSION", "PLUGIN_ID"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MsdpValue {
+    String(String),
+    Array(Vec<MsdpValue>),
+    Table(Vec<(String, MsdpValue)>),
+}
+
+impl MsdpValue {
+    pub fn from_list(items: &[&str]) -> Self {
+        MsdpValue::Array(items.iter().map(|s| MsdpValue::String(s.to_string())).collect())
+    }
+
+    pub fn as_string(&self) -> Option<&str> {
+        match self {
+            MsdpValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn encode_into(&self, b: &mut Vec<u8>) {
+        match self {
+            MsdpValue::String(s) => b.extend_from_slice(s.as_bytes()),
+            MsdpValue::Array(items) => {
+                b.push(MSDP_ARRAY_OPEN);
+                for item in items {
+                    b.push(MSDP_VAL);
+                    item.encode_into(b);
+                }
+                b.push(MSDP_ARRAY_CLOSE);
+            },
+            MsdpValue::Table(items) => {
+                b.push(MSDP_TABLE_OPEN);
+                for (name, item) in items {
+                    b.push(MSDP_VAR);
+                    b.extend_from_slice(name.as_bytes());
+                    b.push(MSDP_VAL);
+                    item.encode_into(b);
+                }
+                b.push(MSDP_TABLE_CLOSE);
+            },
+        }
+    }
+}
+
+// The payload of an MSDP subnegotiation, without the IAC SB MSDP / IAC SE framing
+pub fn encode_variables(variables: &[(String, MsdpValue)]) -> Vec<u8> {
+    let mut b = vec![];
+    for (name, value) in variables {
+        b.push(MSDP_VAR);
+        b.extend_from_slice(name.as_bytes());
+        b.push(MSDP_VAL);
+        value.encode_into(&mut b);
+    }
+    b
+}
+
+pub fn encode_message(variables: &[(String, MsdpValue)]) -> TelnetCommand {
+    subnegotiation(TELOPT_MSDP, &encode_variables(variables))
+}
+
+/*
+ * Decode a subnegotiation payload into variable/value pairs.  A variable
+ * given several values in a row (VAR "REPORT" VAL "A" VAL "B") is treated
+ * as an array of those values.
+ */
+pub fn decode_variables(payload: &[u8]) -> Vec<(String, MsdpValue)> {
+    let mut pos = 0;
+    decode_pairs(payload, &mut pos, None)
+}
+
+fn decode_pairs(payload: &[u8], pos: &mut usize, close: Option<u8>) -> Vec<(String, MsdpValue)> {
+    let mut variables: Vec<(String, MsdpValue)> = vec![];
+
+    while *pos < payload.len() {
+        let byte = payload[*pos];
+        if Some(byte) == close {
+            *pos += 1;
+            break;
+        }
+
+        if byte != MSDP_VAR {
+            // Garbage, skip it
+            *pos += 1;
+            continue;
+        }
+
+        *pos += 1;
+        let name = read_string(payload, pos);
+        let mut values = vec![];
+        while *pos < payload.len() && payload[*pos] == MSDP_VAL {
+            *pos += 1;
+            values.push(decode_value(payload, pos));
+        }
+
+        let value = match values.len() {
+            0 => MsdpValue::String("".to_string()),
+            1 => values.remove(0),
+            _ => MsdpValue::Array(values),
+        };
+        variables.push((name, value));
+    }
+
+    variables
+}
+
+fn decode_value(payload: &[u8], pos: &mut usize) -> MsdpValue {
+    if *pos >= payload.len() {
+        return MsdpValue::String("".to_string());
+    }
+
+    match payload[*pos] {
+        MSDP_TABLE_OPEN => {
+            *pos += 1;
+            MsdpValue::Table(decode_pairs(payload, pos, Some(MSDP_TABLE_CLOSE)))
+        },
+        MSDP_ARRAY_OPEN => {
+            *pos += 1;
+            let mut items = vec![];
+            while *pos < payload.len() {
+                let byte = payload[*pos];
+                *pos += 1;
+                if byte == MSDP_ARRAY_CLOSE {
+                    break;
+                } else if byte == MSDP_VAL {
+                    items.push(decode_value(payload, pos));
+                }
+            }
+            MsdpValue::Array(items)
+        },
+        _ => MsdpValue::String(read_string(payload, pos)),
+    }
+}
+
+fn read_string(payload: &[u8], pos: &mut usize) -> String {
+    let start = *pos;
+    while *pos < payload.len() && payload[*pos] > MSDP_ARRAY_CLOSE {
+        *pos += 1;
+    }
+    String::from_utf8_lossy(&payload[start..*pos]).to_string()
+}
+
+
+/*
+ * The game variables that can be reported or sent.  Game code registers
+ * any extra variables it intends to publish with register_variable().
+ */
+lazy_static! {
+    static ref MSDP_REGISTRY: Arc<RwLock<HashSet<String>>> = Arc::new(RwLock::new(
+        ["SERVER_ID", "SERVER_TIME", "CHARACTER_NAME", "HEALTH", "HEALTH_MAX", "MANA", "MANA_MAX",
+         "MOVEMENT", "MOVEMENT_MAX", "EXPERIENCE", "EXPERIENCE_MAX", "LEVEL", "CLASS", "RACE",
+         "ALIGNMENT", "MONEY", "OPPONENT_NAME", "OPPONENT_HEALTH", "OPPONENT_HEALTH_MAX",
+         "ROOM", "ROOM_NAME", "ROOM_EXITS", "ROOM_VNUM", "AREA_NAME"]
+        .iter().map(|s| s.to_string()).collect()
+    ));
+}
+
+#[allow(unused)]
+pub fn register_variable(name: &str) {
+    MSDP_REGISTRY.write().unwrap().insert(name.to_uppercase());
+}
+
+pub fn is_reportable(name: &str) -> bool {
+    MSDP_REGISTRY.read().unwrap().contains(&name.to_uppercase())
+}
+
+pub fn reportable_variables() -> Vec<String> {
+    let mut names: Vec<String> = MSDP_REGISTRY.read().unwrap().iter().cloned().collect();
+    names.sort();
+    names
+}
+
+
+/*
+ * Per-connection MSDP state.  Holds the latest value of every variable the
+ * game has published to this player, and which ones the client wants pushed
+ * to it whenever they change.  All methods return the subnegotiations to send.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MsdpState {
+    pub reported: HashSet<String>,
+    pub values: HashMap<String, MsdpValue>,
+    pub configurable: HashMap<String, String>,
+}
+
+impl MsdpState {
+    pub fn handle_payload(&mut self, payload: &[u8]) -> Vec<TelnetCommand> {
+        let mut responses = vec![];
+
+        for (name, value) in decode_variables(payload) {
+            let args: Vec<String> = match &value {
+                MsdpValue::String(s) => vec![s.clone()],
+                MsdpValue::Array(items) => items.iter().filter_map(|v| v.as_string()).map(|s| s.to_string()).collect(),
+                MsdpValue::Table(_) => vec![],
+            };
+
+            match name.as_str() {
+                "LIST" => {
+                    for arg in &args {
+                        if let Some(list) = self.list(arg) {
+                            responses.push(encode_message(&[(arg.clone(), list)]));
+                        }
+                    }
+                },
+                "REPORT" => {
+                    for arg in &args {
+                        // Variables are kept in upper case, whatever the client sends
+                        let arg = arg.to_uppercase();
+                        if is_reportable(&arg) {
+                            self.reported.insert(arg.clone());
+                            // The current value is sent straight away
+                            if let Some(value) = self.values.get(&arg) {
+                                responses.push(encode_message(&[(arg, value.clone())]));
+                            }
+                        }
+                    }
+                },
+                "UNREPORT" => {
+                    for arg in &args {
+                        self.reported.remove(&arg.to_uppercase());
+                    }
+                },
+                "RESET" => {
+                    for arg in &args {
+                        if arg == "REPORTABLE_VARIABLES" || arg == "REPORTED_VARIABLES" {
+                            self.reported.clear();
+                        }
+                    }
+                },
+                "SEND" => {
+                    let values: Vec<(String, MsdpValue)> = args.iter()
+                        .map(|arg| arg.to_uppercase())
+                        .filter_map(|arg| self.values.get(&arg).map(|v| (arg.clone(), v.clone())))
+                        .collect();
+                    if !values.is_empty() {
+                        responses.push(encode_message(&values));
+                    }
+                },
+                _ => {
+                    if CONFIGURABLE_VARIABLES.contains(&name.as_str()) && !args.is_empty() {
+                        self.configurable.insert(name.clone(), args[0].clone());
+                    }
+                },
+            }
+        }
+
+        responses
+    }
+
+    fn list(&self, name: &str) -> Option<MsdpValue> {
+        match name {
+            "COMMANDS" => Some(MsdpValue::from_list(&COMMANDS)),
+            "LISTS" => Some(MsdpValue::from_list(&LISTS)),
+            "CONFIGURABLE_VARIABLES" => Some(MsdpValue::from_list(&CONFIGURABLE_VARIABLES)),
+            "REPORTABLE_VARIABLES" | "SENDABLE_VARIABLES" => {
+                let names = reportable_variables();
+                Some(MsdpValue::Array(names.into_iter().map(MsdpValue::String).collect()))
+            },
+            "REPORTED_VARIABLES" => {
+                let mut names: Vec<String> = self.reported.iter().cloned().collect();
+                names.sort();
+                Some(MsdpValue::Array(names.into_iter().map(MsdpValue::String).collect()))
+            },
+            _ => None,
+        }
+    }
+
+    // Record a new value, and send it if it changed and the client asked for it
+    pub fn update(&mut self, name: &str, value: MsdpValue) -> Option<TelnetCommand> {
+        let name = name.to_uppercase();
+        let changed = self.values.get(&name) != Some(&value);
+        self.values.insert(name.clone(), value.clone());
+
+        if changed && self.reported.contains(&name) {
+            Some(encode_message(&[(name, value)]))
+        } else {
+            None
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The MSDP payload of a subnegotiation sent back to the client
+    fn payload(command: &TelnetCommand) -> Vec<u8> {
+        let bytes = command.as_bytes();
+        assert_eq!(&bytes[..3], &[IAC, SB, TELOPT_MSDP]);
+        assert_eq!(&bytes[bytes.len() - 2..], &[IAC, SE]);
+        bytes[3..bytes.len() - 2].to_vec()
+    }
+
+    fn request(command: &str, args: &[&str]) -> Vec<u8> {
+        encode_variables(&[(command.to_string(), MsdpValue::from_list(args))])
+    }
+
+    #[test]
+    fn encode_string() {
+        let encoded = encode_variables(&[("HEALTH".to_string(), MsdpValue::String("100".to_string()))]);
+        assert_eq!(encoded, [&[MSDP_VAR][..], b"HEALTH", &[MSDP_VAL], b"100"].concat());
+    }
+
+    #[test]
+    fn encode_array_and_table() {
+        let value = MsdpValue::Table(vec![
+            ("NAME".to_string(), MsdpValue::String("Hall".to_string())),
+            ("EXITS".to_string(), MsdpValue::from_list(&["n", "s"])),
+        ]);
+        let encoded = encode_variables(&[("ROOM".to_string(), value)]);
+        let expected = [&[MSDP_VAR][..], b"ROOM", &[MSDP_VAL, MSDP_TABLE_OPEN, MSDP_VAR], b"NAME",
+                        &[MSDP_VAL], b"Hall", &[MSDP_VAR], b"EXITS",
+                        &[MSDP_VAL, MSDP_ARRAY_OPEN, MSDP_VAL], b"n", &[MSDP_VAL], b"s",
+                        &[MSDP_ARRAY_CLOSE, MSDP_TABLE_CLOSE]].concat();
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        let variables = vec![
+            ("HEALTH".to_string(), MsdpValue::String("100".to_string())),
+            ("ROOM".to_string(), MsdpValue::Table(vec![
+                ("VNUM".to_string(), MsdpValue::String("3001".to_string())),
+                ("EXITS".to_string(), MsdpValue::from_list(&["n", "e"])),
+            ])),
+            ("GROUP".to_string(), MsdpValue::Array(vec![])),
+        ];
+        assert_eq!(decode_variables(&encode_variables(&variables)), variables);
+    }
+
+    #[test]
+    fn decode_repeated_values_as_array() {
+        let payload = [&[MSDP_VAR][..], b"REPORT", &[MSDP_VAL], b"HEALTH", &[MSDP_VAL], b"MANA"].concat();
+        assert_eq!(decode_variables(&payload),
+                   vec![("REPORT".to_string(), MsdpValue::from_list(&["HEALTH", "MANA"]))]);
+    }
+
+    #[test]
+    fn decode_skips_garbage() {
+        let payload = [b"junk", &[MSDP_VAR][..], b"LEVEL", &[MSDP_VAL], b"5"].concat();
+        assert_eq!(decode_variables(&payload), vec![("LEVEL".to_string(), MsdpValue::String("5".to_string()))]);
+    }
+
+    #[test]
+    fn list_commands() {
+        let mut state = MsdpState::default();
+        let responses = state.handle_payload(&request("LIST", &["COMMANDS"]));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(decode_variables(&payload(&responses[0])),
+                   vec![("COMMANDS".to_string(), MsdpValue::from_list(&COMMANDS))]);
+    }
+
+    #[test]
+    fn list_unknown_is_ignored() {
+        let mut state = MsdpState::default();
+        assert!(state.handle_payload(&request("LIST", &["NONSENSE"])).is_empty());
+    }
+
+    #[test]
+    fn report_sends_current_value_then_changes() {
+        let mut state = MsdpState::default();
+        assert!(state.update("HEALTH", MsdpValue::String("90".to_string())).is_none());
+
+        let responses = state.handle_payload(&request("REPORT", &["HEALTH"]));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(decode_variables(&payload(&responses[0])),
+                   vec![("HEALTH".to_string(), MsdpValue::String("90".to_string()))]);
+
+        // Only a change is sent
+        assert!(state.update("HEALTH", MsdpValue::String("90".to_string())).is_none());
+        let update = state.update("HEALTH", MsdpValue::String("80".to_string())).unwrap();
+        assert_eq!(decode_variables(&payload(&update)),
+                   vec![("HEALTH".to_string(), MsdpValue::String("80".to_string()))]);
+    }
+
+    #[test]
+    fn report_is_case_insensitive() {
+        register_variable("quest_points");
+        assert!(is_reportable("QUEST_POINTS"));
+        assert!(is_reportable("quest_points"));
+
+        let mut state = MsdpState::default();
+        state.handle_payload(&request("REPORT", &["mana"]));
+        assert!(state.reported.contains("MANA"));
+        assert!(state.update("mana", MsdpValue::String("50".to_string())).is_some());
+
+        state.handle_payload(&request("UNREPORT", &["Mana"]));
+        assert!(state.update("MANA", MsdpValue::String("40".to_string())).is_none());
+    }
+
+    #[test]
+    fn report_unknown_variable_is_ignored() {
+        let mut state = MsdpState::default();
+        state.handle_payload(&request("REPORT", &["NOT_A_VARIABLE"]));
+        assert!(state.reported.is_empty());
+    }
+
+    #[test]
+    fn list_reported_variables() {
+        let mut state = MsdpState::default();
+        state.handle_payload(&request("REPORT", &["MANA", "HEALTH"]));
+        let responses = state.handle_payload(&request("LIST", &["REPORTED_VARIABLES"]));
+        assert_eq!(decode_variables(&payload(&responses[0])),
+                   vec![("REPORTED_VARIABLES".to_string(), MsdpValue::from_list(&["HEALTH", "MANA"]))]);
+
+        state.handle_payload(&request("RESET", &["REPORTED_VARIABLES"]));
+        assert!(state.reported.is_empty());
+    }
+}
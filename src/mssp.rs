@@ -0,0 +1,72 @@
+use crate::server::Server;
+use crate::settings::{Listener, Protocol};
+use crate::telnet::*;
+use std::time::UNIX_EPOCH;
+
+/*
+ * MUD Server Status Protocol, for the MUD listing site crawlers.
+ *     IAC SB MSSP MSSP_VAR "PLAYERS" MSSP_VAL "52" ... IAC SE
+ * Crawlers that can't do telnet negotiation send "MSSP-REQUEST" as a line
+ * instead, and get the same variables back as plain text.
+ */
+pub const MSSP_VAR: u8 = 1;
+pub const MSSP_VAL: u8 = 2;
+
+pub const MSSP_REQUEST: &str = "MSSP-REQUEST";
+
+pub async fn mssp_variables() -> Vec<(String, String)> {
+    let settings = {
+        Server::get(None).await.write().await.get_settings()
+    };
+    let status = Server::get_status().await;
+
+    let uptime = status.started.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    let mut variables = vec![];
+    if let Some(settings) = settings {
+        variables.push(("NAME".to_string(), settings.mud.name.clone()));
+        variables.push(("HOSTNAME".to_string(), settings.mud.hostname.clone()));
+        // Only the ports anyone can play on
+        let listeners: Vec<Listener> = settings.get_listeners().into_iter().filter(|l| !l.immortal_only && !l.builder).collect();
+        let telnet = listeners.iter().find(|l| l.protocol == Protocol::Telnet);
+        let tls = listeners.iter().find(|l| l.protocol == Protocol::Tls);
+        variables.push(("PORT".to_string(), telnet.map(|l| l.port).unwrap_or(settings.mud.port).to_string()));
+        if let Some(tls) = tls {
+            variables.push(("SSL".to_string(), tls.port.to_string()));
+        }
+    }
+
+    variables.push(("PLAYERS".to_string(), status.players.to_string()));
+    variables.push(("UPTIME".to_string(), uptime.to_string()));
+    variables.push(("CODEBASE".to_string(), format!("HavokMudRust {}", env!("CARGO_PKG_VERSION"))));
+    variables.push(("FAMILY".to_string(), "DikuMUD".to_string()));
+    variables.push(("LANGUAGE".to_string(), "English".to_string()));
+    variables.push(("CRAWL DELAY".to_string(), "-1".to_string()));
+    variables.push(("ANSI".to_string(), "1".to_string()));
+    variables.push(("GMCP".to_string(), "1".to_string()));
+    variables.push(("MCCP".to_string(), "1".to_string()));
+    variables.push(("MSDP".to_string(), "1".to_string()));
+    variables.push(("MSSP".to_string(), "1".to_string()));
+
+    variables
+}
+
+pub fn encode_telnet(variables: &[(String, String)]) -> TelnetCommand {
+    let mut payload = vec![];
+    for (name, value) in variables {
+        payload.push(MSSP_VAR);
+        payload.extend_from_slice(name.as_bytes());
+        payload.push(MSSP_VAL);
+        payload.extend_from_slice(value.as_bytes());
+    }
+    subnegotiation(TELOPT_MSSP, &payload)
+}
+
+pub fn encode_text(variables: &[(String, String)]) -> String {
+    let mut output = "\r\nMSSP-REPLY-START\r\n".to_string();
+    for (name, value) in variables {
+        output.push_str(&format!("{}\t{}\r\n", name, value));
+    }
+    output.push_str("MSSP-REPLY-END\r\n");
+    output
+}
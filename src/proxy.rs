@@ -0,0 +1,237 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/*
+ * HAProxy PROXY protocol, which a load balancer sends at the start of the
+ * connection to say who the real client is.  Version 1 is a text line:
+ *     PROXY TCP4 192.0.2.1 198.51.100.1 56324 3000\r\n
+ * Version 2 is binary, starting with a 12 byte signature, then the version
+ * and command, the address family, and the length of the addresses.
+ */
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+
+const V2_VERSION: u8 = 0x20;
+const V2_CMD_LOCAL: u8 = 0x00;
+const V2_CMD_PROXY: u8 = 0x01;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/*
+ * Read the PROXY header from the start of a new connection, taking exactly
+ * the header and nothing after it.  Returns the client's real address, or
+ * None if the proxy didn't give one (a health check, or an unknown family).
+ */
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, String> {
+    let mut start = [0u8; 6];
+    stream.read_exact(&mut start).await.map_err(|e| e.to_string())?;
+
+    if start == V1_PREFIX {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err("PROXY v1 header too long".to_string());
+            }
+            line.push(stream.read_u8().await.map_err(|e| e.to_string())?);
+        }
+        return parse_v1(&line[..line.len() - 2]);
+    }
+
+    if start != V2_SIGNATURE[..6] {
+        return Err("No PROXY header".to_string());
+    }
+
+    let mut header = [0u8; 10];
+    stream.read_exact(&mut header).await.map_err(|e| e.to_string())?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err("Bad PROXY v2 signature".to_string());
+    }
+
+    let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await.map_err(|e| e.to_string())?;
+    parse_v2(header[6], header[7], &addresses)
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, String> {
+    let line = String::from_utf8_lossy(line).to_string();
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    if fields.len() >= 2 && fields[1] == "UNKNOWN" {
+        return Ok(None);
+    }
+
+    if fields.len() != 6 || (fields[1] != "TCP4" && fields[1] != "TCP6") {
+        return Err(format!("Bad PROXY v1 header: {}", line));
+    }
+
+    let ip: IpAddr = fields[2].parse().map_err(|_| format!("Bad PROXY v1 address: {}", fields[2]))?;
+    if ip.is_ipv4() != (fields[1] == "TCP4") {
+        return Err(format!("PROXY v1 address {} isn't {}", fields[2], fields[1]));
+    }
+    let port: u16 = fields[4].parse().map_err(|_| format!("Bad PROXY v1 port: {}", fields[4]))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>, String> {
+    if version_command & 0xF0 != V2_VERSION {
+        return Err(format!("Unknown PROXY version {:#x}", version_command >> 4));
+    }
+
+    match version_command & 0x0F {
+        V2_CMD_LOCAL => return Ok(None),
+        V2_CMD_PROXY => {},
+        command => return Err(format!("Unknown PROXY v2 command {:#x}", command)),
+    }
+
+    match family {
+        V2_TCP4 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        },
+        V2_TCP6 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        },
+        V2_TCP4 | V2_TCP6 => Err("PROXY v2 addresses too short".to_string()),
+        _ => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn v2_header(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([version_command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    // Write the bytes into one end of a pipe and read the header off the other
+    async fn read_from(data: &[u8]) -> (Result<Option<SocketAddr>, String>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(data).await.unwrap();
+        drop(client);
+
+        let result = read_header(&mut server).await;
+        let mut rest = vec![];
+        server.read_to_end(&mut rest).await.unwrap();
+        (result, rest)
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let addr = parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 3000").unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 3000").unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        assert_eq!(parse_v1(b"PROXY UNKNOWN").unwrap(), None);
+        assert_eq!(parse_v1(b"PROXY UNKNOWN ffff::1 ffff::2 1 2").unwrap(), None);
+    }
+
+    #[test]
+    fn v1_wrong_family() {
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 3000").is_err());
+        assert!(parse_v1(b"PROXY TCP6 192.0.2.1 2001:db8::2 56324 3000").is_err());
+    }
+
+    #[test]
+    fn v1_malformed() {
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324").is_err());
+        assert!(parse_v1(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 3000").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 port 3000").is_err());
+    }
+
+    #[test]
+    fn v2_local() {
+        assert_eq!(parse_v2(V2_VERSION | V2_CMD_LOCAL, 0, &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn v2_tcp4_with_tlvs() {
+        // Addresses, ports, then a NOOP TLV
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x0B, 0xB8, 0x04, 0x00, 0x01, 0x00];
+        let addr = parse_v2(V2_VERSION | V2_CMD_PROXY, V2_TCP4, &addresses).unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend([0xDC, 0x04, 0x0B, 0xB8]);
+        let addr = parse_v2(V2_VERSION | V2_CMD_PROXY, V2_TCP6, &addresses).unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_short_addresses() {
+        assert!(parse_v2(V2_VERSION | V2_CMD_PROXY, V2_TCP4, &[192, 0, 2, 1, 198, 51, 100, 1, 0xDC]).is_err());
+        assert!(parse_v2(V2_VERSION | V2_CMD_PROXY, V2_TCP6, &[0; 35]).is_err());
+    }
+
+    #[test]
+    fn v2_bad_version_or_command() {
+        assert!(parse_v2(0x10 | V2_CMD_PROXY, V2_TCP4, &[0; 12]).is_err());
+        assert!(parse_v2(V2_VERSION | 0x02, V2_TCP4, &[0; 12]).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_v1_leaves_the_rest() {
+        let (result, rest) = read_from(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 3000\r\nhello").await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn read_v1_too_long() {
+        let mut line = b"PROXY UNKNOWN ".to_vec();
+        line.extend(vec![b'x'; V1_MAX_LENGTH]);
+        line.extend(b"\r\n");
+        assert!(read_from(&line).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_v2_leaves_the_rest() {
+        let mut data = v2_header(V2_VERSION | V2_CMD_PROXY, V2_TCP4, &[192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x0B, 0xB8]);
+        data.extend(b"hello");
+        let (result, rest) = read_from(&data).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+
+        let (result, _) = read_from(&v2_header(V2_VERSION | V2_CMD_LOCAL, 0, &[])).await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn read_bad_signature() {
+        let mut data = v2_header(V2_VERSION | V2_CMD_PROXY, V2_TCP4, &[0; 12]);
+        data[10] = 0;
+        assert!(read_from(&data).await.0.is_err());
+        assert!(read_from(b"GET / HTTP/1.1\r\n").await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_short_addresses() {
+        let mut data = v2_header(V2_VERSION | V2_CMD_PROXY, V2_TCP4, &[0; 12]);
+        data.truncate(data.len() - 4);
+        assert!(read_from(&data).await.0.is_err());
+    }
+}
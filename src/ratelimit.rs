@@ -0,0 +1,44 @@
+use std::time::Instant;
+
+/*
+ * Token bucket rate limiter.  Holds up to `burst` tokens, refilled at `rate`
+ * tokens per second, and each event takes one.  A rate of 0 means no limit.
+ */
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let burst = if burst < 1 { 1.0 } else { burst as f64 };
+        TokenBucket {
+            rate: rate as f64,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    // Returns false if the event is over the limit
+    pub fn take(&mut self) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
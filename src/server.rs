@@ -1,26 +1,72 @@
 extern crate tokio;
 
-use crate::settings::Settings;
-use crate::connection::Connection;
+use crate::settings::{Listener, Protocol, Settings};
+use crate::connection::{Connection, ConnectionState};
+use crate::countdown::{describe_seconds, Countdown};
+use crate::compress::OutputCompressor;
+use crate::ratelimit::TokenBucket;
+use crate::bans::{self, BanType};
+use crate::tls;
+use crate::websocket;
+use crate::proxy;
+use crate::copyover::{self, SavedConnection};
 use crate::logging::*;
 use crate::ControlSignal;
-use std::net::SocketAddr;
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::net::tcp::OwnedWriteHalf;
+use crate::supervisor::{Health, TaskContext};
+use crate::control::{save_and_shutdown, ControlReceiver, Request, Response};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
 use tokio::net::{TcpSocket, TcpListener, TcpStream};
 use tokio::io;
-use tokio::io::{AsyncWriteExt, AsyncReadExt};
-use tokio::sync::{broadcast, mpsc, RwLock, Barrier};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncReadExt};
+use tokio_rustls::TlsAcceptor;
+use tokio::sync::{broadcast, mpsc, RwLock};
 use std::sync::Arc;
-use tokio::task;
+use tokio::task::AbortHandle;
 use std::collections::HashMap;
 use tokio::task::JoinHandle;
 use bytes::BytesMut;
+use std::time::{Duration, SystemTime};
+use socket2::{SockRef, TcpKeepalive};
+
+// How often connections are checked for being idle
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Time a client gets to finish the TLS or WebSocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A new connection, and the address of the listener it came in on
+#[derive(Debug)]
+pub struct Accepted {
+    stream: TcpStream,
+    addr: SocketAddr,
+    listener: String,
+    // The PROXY header has been read, and addr is the real client
+    proxied: bool,
+}
+
+// Either half of a plain TCP or TLS connection
+pub trait StreamRead: AsyncRead + Send + Sync + Unpin + fmt::Debug {}
+impl<T: AsyncRead + Send + Sync + Unpin + fmt::Debug> StreamRead for T {}
+pub type StreamReader = Box<dyn StreamRead>;
+
+pub trait StreamWrite: AsyncWrite + Send + Sync + Unpin + fmt::Debug {}
+impl<T: AsyncWrite + Send + Sync + Unpin + fmt::Debug> StreamWrite for T {}
+pub type StreamWriter = Box<dyn StreamWrite>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamControl {
+    None,
+    StartCompression,
+    EndCompression,
+}
 
 #[derive(Debug, Clone)]
 pub struct NetworkMessage {
     pub dest: SocketAddr,
     pub data: Vec<u8>,
+    pub control: StreamControl,
 }
 
 
@@ -34,13 +80,64 @@ pub struct Server {
     wizlock_reason: String,
     settings: Option<Settings>,
     pub connections: HashMap<SocketAddr, Connection>,
-    pub wr_streams: HashMap<SocketAddr, Arc<RwLock<OwnedWriteHalf>>>,
-    pub rd_streams: HashMap<SocketAddr, Arc<RwLock<OwnedReadHalf>>>,
+    pub wr_streams: HashMap<SocketAddr, Arc<RwLock<StreamWriter>>>,
+    pub rd_streams: HashMap<SocketAddr, Arc<RwLock<StreamReader>>>,
     pub rd_handles: HashMap<SocketAddr, Arc<RwLock<JoinHandle<()>>>>,
+    pub compressors: HashMap<SocketAddr, Arc<RwLock<OutputCompressor>>>,
+    // Sockets of the plain telnet connections, which can be kept over a copyover
+    pub fds: HashMap<SocketAddr, RawFd>,
+    pub listeners: HashMap<String, Listener>,
+    listener_tasks: HashMap<String, AbortHandle>,
+    accept_bucket: TokenBucket,
+}
+
+/*
+ * Lock (or unlock) the game to mortals.  With a grace period, mortals who
+ * are already playing get a countdown and are then disconnected.
+ */
+#[derive(Debug, Clone)]
+pub struct Wizlock {
+    pub locked: bool,
+    pub reason: String,
+    pub grace: Option<u64>,
+}
+
+/*
+ * Shut the game down (or reboot it) after warning the players.  Without a
+ * countdown, the one in the settings is used.
+ */
+#[derive(Debug, Clone)]
+pub struct ShutdownRequest {
+    pub reason: String,
+    pub countdown: Option<u64>,
+    pub reboot: bool,
+}
+
+impl ShutdownRequest {
+    // "shutting down" or "rebooting", for the messages to players
+    pub fn describe(&self) -> &'static str {
+        if self.reboot { "rebooting" } else { "shutting down" }
+    }
+}
+
+// Live server information for anything outside the server thread
+#[derive(Debug, Clone)]
+pub struct ServerStatus {
+    pub started: SystemTime,
+    pub players: usize,
+    pub wizlocked: bool,
+    pub wizlock_reason: String,
 }
 
 use lazy_static::lazy_static;
 lazy_static! {
+    static ref SERVER_STATUS: Arc<RwLock<ServerStatus>> = Arc::new(RwLock::new(ServerStatus {
+        started: SystemTime::now(),
+        players: 0,
+        wizlocked: false,
+        wizlock_reason: "".to_string(),
+    }));
+
     static ref SERVER: Arc<RwLock<Server>> = Arc::new(RwLock::new(Server {
         initialized: false,
         bind_ip: "".to_string(),
@@ -52,6 +149,11 @@ lazy_static! {
         wr_streams: HashMap::new(),
         rd_streams: HashMap::new(),
         rd_handles: HashMap::new(),
+        compressors: HashMap::new(),
+        fds: HashMap::new(),
+        listeners: HashMap::new(),
+        listener_tasks: HashMap::new(),
+        accept_bucket: TokenBucket::new(0, 0),
     }));
 }
 
@@ -83,56 +185,262 @@ impl Server {
             self.wr_streams.clear();
             self.rd_streams.clear();
             self.rd_handles.clear();
+            self.compressors.clear();
+            self.fds.clear();
+            self.accept_bucket = TokenBucket::new(conf.limits.max_accepts_per_second, conf.limits.accept_burst);
+            bans::load_bans(&conf.global.data_dir);
             self.initialized = true;
         }
     }
 
-    pub fn start_server(&mut self) -> Arc<RwLock<Option<TcpListener>>> {
-        let mut listener = None;
-
-        if !self.settings.is_none() {
-            let addr = format!("{}:{}", self.bind_ip, self.port);
-            let bind_addr: SocketAddr = addr.parse().unwrap_or_else(|e| panic!("Bind address {} is invalid: {:?}", addr, e));
-            let socket;
-            
-            if bind_addr.is_ipv4() {
-                socket = TcpSocket::new_v4().unwrap_or_else(|e| panic!("Couldn't create IPv4 socket: {:?}", e));
-            } else if bind_addr.is_ipv6() {
-                socket = TcpSocket::new_v6().unwrap_or_else(|e| panic!("Couldn't create IPv6 socket: {:?}", e));
-            } else {
-                panic!("We cannot determine if this is IPv4 or IPv6!: {}", addr);
+    // Take on new settings without disturbing the connections we already have
+    pub async fn reconfigure(&mut self, settings: Settings) {
+        let old_limits = self.settings.as_ref().unwrap().limits.clone();
+        if settings.limits.max_accepts_per_second != old_limits.max_accepts_per_second
+            || settings.limits.accept_burst != old_limits.accept_burst {
+            self.accept_bucket = TokenBucket::new(settings.limits.max_accepts_per_second, settings.limits.accept_burst);
+        }
+
+        self.bind_ip = settings.mud.bind_ip.clone();
+        self.port = settings.mud.port;
+        self.settings = Some(settings.clone());
+        SERVER.write().await.settings = Some(settings);
+    }
+
+    /*
+     * Start listening on any addresses in the settings that we aren't yet,
+     * and stop on any that have been taken out.  Each listener has a task
+     * that hands accepted connections to the server thread, which looks up
+     * the listener's current protocol and flags, so changing those needs no
+     * restart.  Connections already made are left alone.
+     */
+    pub fn update_listeners(&mut self, accept_sender: &mpsc::Sender<Accepted>) {
+        let mut wanted: HashMap<String, Listener> = HashMap::new();
+        for listener in self.settings.as_ref().unwrap().get_listeners() {
+            wanted.insert(listener.address(), listener);
+        }
+
+        let stale: Vec<String> = self.listeners.keys().filter(|k| !wanted.contains_key(*k)).cloned().collect();
+        for address in stale {
+            log_info(&format!("Closing listener on {}", address));
+            self.listeners.remove(&address);
+            if let Some(task) = self.listener_tasks.remove(&address) {
+                task.abort();
+            }
+        }
+
+        for (address, listener) in wanted {
+            if !self.listener_tasks.contains_key(&address) {
+                match bind_listener(&address) {
+                    Ok(tcp_listener) => {
+                        log_info(&format!("Listening for {:?} connections on {}", listener.protocol, address));
+                        let task_sender = accept_sender.clone();
+                        let task_address = address.clone();
+                        let handle = tokio::spawn(async move {
+                            do_listener_thread(tcp_listener, task_address, task_sender).await;
+                        });
+                        self.listener_tasks.insert(address.clone(), handle.abort_handle());
+                    },
+                    Err(e) => {
+                        log_error(&format!("Could not listen on {}: {:?}", address, e));
+                        continue;
+                    },
+                }
+            }
+            self.listeners.insert(address, listener);
+        }
+    }
+
+    // Past the shutdown deadline, so drop what's left without waiting on the clients
+    async fn force_close(&mut self) {
+        log_error(&format!("Forcing {} connections closed", self.wr_streams.len()));
+        self.stop_readers().await;
+        self.wr_streams.clear();
+        self.rd_streams.clear();
+        self.compressors.clear();
+        self.fds.clear();
+        self.connections.clear();
+    }
+
+    // Read threads run until their client goes away, or the server goes down
+    async fn stop_readers(&mut self) {
+        for (_, handle) in self.rd_handles.drain() {
+            handle.read().await.abort();
+        }
+    }
+
+    pub fn stop_listeners(&mut self) {
+        for (_, task) in self.listener_tasks.drain() {
+            task.abort();
+        }
+        self.listeners.clear();
+    }
+
+    /*
+     * Set up everything for a newly accepted connection, whether plain or
+     * TLS, and start it up.  A connection carried over by a copyover picks
+     * up where it left off instead.
+     */
+    #[allow(clippy::too_many_arguments)]
+    async fn add_connection(&mut self, reader: StreamReader, writer: StreamWriter, addr: SocketAddr, listener: &Listener,
+                            txsender: &mpsc::Sender<NetworkMessage>, rxsender: &mpsc::Sender<NetworkMessage>,
+                            ctlsender: &broadcast::Sender<ControlSignal>, saved: Option<SavedConnection>) {
+        let settings = self.get_settings().unwrap();
+        let rd_stream = Arc::new(RwLock::new(reader));
+        self.rd_streams.insert(addr, rd_stream.clone());
+        self.wr_streams.insert(addr, Arc::new(RwLock::new(writer)));
+
+        let rd_ctlrx = ControlReceiver::new("connection", ctlsender);
+        let rd_dataqueue = rxsender.clone();
+        let rd_handle = tokio::spawn(async move {
+            do_read_thread(rd_ctlrx, &rd_dataqueue, addr, rd_stream.clone()).await; 
+        });
+        self.rd_handles.insert(addr, Arc::new(RwLock::new(rd_handle)));
+
+        let mut connection = Connection::new(txsender, addr, &settings, ctlsender, listener).await;
+        if let Some(saved) = saved {
+            connection.resume_processing(saved).await;
+            self.connections.insert(addr, connection.clone());
+            self.update_status().await;
+            return;
+        }
+
+        connection.start_processing().await;
+        self.connections.insert(addr, connection.clone());
+        self.update_status().await;
+        connection.send_line(txsender, format!("Hi! $c020PWelcome$c0007 to $c000b{}", settings.mud.name)).await;
+    }
+
+    /*
+     * Hand every connection we can over to a new copy of the binary.  The
+     * rest are told to come back in a moment.  Only returns if the exec
+     * failed, and then everyone carries on.
+     */
+    async fn copyover(&mut self, by: &str, txreceiver: &mut mpsc::Receiver<NetworkMessage>) {
+        log_info(&format!("Starting copyover for {}", by));
+        let data_dir = self.get_settings().unwrap().global.data_dir;
+
+        // Each one's output goes out as we go, closing the ones we aren't keeping
+        for addr in self.addresses() {
+            let keep = self.fds.contains_key(&addr);
+            if let Some(connection) = self.connections.get_mut(&addr) {
+                if !keep || !connection.can_copyover() {
+                    connection.disconnect("The game is rebooting, please reconnect in a moment.".to_string()).await;
+                } else {
+                    connection.prepare_copyover().await;
+                }
+            }
+            self.flush_queue(txreceiver).await;
+        }
+
+        let mut saved = vec![];
+        for (addr, connection) in self.connections.iter() {
+            let fd = *self.fds.get(addr).unwrap();
+            match copyover::keep_open(fd) {
+                Ok(_) => saved.push(connection.save_state(fd)),
+                Err(e) => log_error(&format!("Couldn't keep the socket for {:?} open: {}", addr, e)),
             }
+        }
+
+        let error = match copyover::save(&data_dir, &saved) {
+            Ok(path) => {
+                log_info(&format!("Saved {} connections to {}, starting the new binary", saved.len(), path));
+                copyover::exec(&path)
+            },
+            Err(e) => e,
+        };
 
-            log_info(&format!("Binding to {}", addr));
-            let _ = socket.set_reuseaddr(true);
-            let _ = socket.bind(bind_addr);
-            listener = Some(socket.listen(1024).unwrap_or_else(|e| panic!("Could not listen on {}: {:?}", addr, e)));
+        log_error(&format!("Copyover failed: {}", error));
+        for addr in self.addresses() {
+            if let Some(connection) = self.connections.get_mut(&addr) {
+                connection.copyover_failed().await;
+            }
+            self.flush_queue(txreceiver).await;
+        }
+    }
+
+    // Take back the connections handed over by the old process
+    async fn recover_connections(&mut self, path: &str, txsender: &mpsc::Sender<NetworkMessage>,
+                                 rxsender: &mpsc::Sender<NetworkMessage>, ctlsender: &broadcast::Sender<ControlSignal>) {
+        let saved = match copyover::load(path) {
+            Ok(saved) => saved,
+            Err(e) => {
+                log_error(&format!("Couldn't recover connections from copyover: {}", e));
+                return;
+            },
+        };
+
+        log_info(&format!("Recovering {} connections from copyover", saved.len()));
+        for connection in saved {
+            let addr = connection.addr;
+            match copyover::restore_stream(connection.fd) {
+                Ok(stream) => {
+                    self.fds.insert(addr, connection.fd);
+                    let listener = connection.listener.clone();
+                    let (rd_half, wr_half) = stream.into_split();
+                    self.add_connection(Box::new(rd_half), Box::new(wr_half), addr, &listener,
+                                        txsender, rxsender, ctlsender, Some(connection)).await;
+                },
+                Err(e) => log_error(&format!("Couldn't recover the connection from {:?}: {:?}", addr, e)),
+            }
         }
-        return Arc::new(RwLock::new(listener));
     }
 
  
     pub async fn send_message(&mut self, message: NetworkMessage) {
         let msgdata = message.data.as_slice();
         let data_len = message.data.len();
-        let disconnect: bool = data_len == 0;
+        let disconnect: bool = data_len == 0 && message.control == StreamControl::None;
         let wr_streams = &mut self.wr_streams;
         let rd_streams = &mut self.rd_streams;
         let addr = message.dest.clone();
 
         match wr_streams.get_mut(&addr) {
             Some(item) => {
+                let compressor = self.compressors.get(&addr).cloned();
                 if disconnect {
                     log_info(&format!("Disconnecting {:?}", addr));
+                    if let Some(compressor) = compressor {
+                        // Cleanly end the MCCP2 stream before closing
+                        let data = compressor.write().await.finish();
+                        write_message(item, &data).await;
+                        self.compressors.remove(&addr);
+                    }
                     shutdown_stream(item).await;
                     let wr_stream = wr_streams.remove(&addr);
                     let rd_stream = rd_streams.remove(&addr);
                     drop(wr_stream);
                     drop(rd_stream);
+                    self.fds.remove(&addr);
                     self.connections.remove(&addr);
+                    self.update_status().await;
                 } else {
-                    log_info(&format!("Sending {} bytes of data to {:?}", data_len, addr));
-                    write_message(item, msgdata).await;
+                    if data_len != 0 {
+                        log_info(&format!("Sending {} bytes of data to {:?}", data_len, addr));
+                        match compressor {
+                            Some(compressor) => {
+                                let data = compressor.write().await.compress(msgdata);
+                                write_message(item, &data).await;
+                            },
+                            None => write_message(item, msgdata).await,
+                        }
+                    }
+
+                    match message.control {
+                        StreamControl::StartCompression => {
+                            // Everything after IAC SB MCCP2 IAC SE is compressed
+                            log_info(&format!("Starting compression for {:?}", addr));
+                            self.compressors.insert(addr, Arc::new(RwLock::new(OutputCompressor::new())));
+                        },
+                        StreamControl::EndCompression => {
+                            if let Some(compressor) = self.compressors.remove(&addr) {
+                                log_info(&format!("Ending compression for {:?}", addr));
+                                let data = compressor.write().await.finish();
+                                write_message(item, &data).await;
+                            }
+                        },
+                        StreamControl::None => {},
+                    }
                 }
             },
             None => {},
@@ -160,13 +468,188 @@ impl Server {
     }
 
 
+    /*
+     * Decide whether to take a new connection, before anything is set up
+     * for it.  Returns the reason to give the client if not.
+     */
+    pub fn check_accept(&mut self, addr: SocketAddr) -> Option<String> {
+        let limits = self.settings.as_ref().unwrap().limits.clone();
+
+        if !self.accept_bucket.take() {
+            return Some("Too many connections at once, please try again in a moment.".to_string());
+        }
+
+        if let Some(ban) = bans::check_ban(addr.ip(), &[]).filter(|b| b.ban_type == BanType::Total) {
+            return Some(format!("Your site has been banned: {}", ban.reason));
+        }
+
+        if limits.max_connections != 0 && self.connections.len() >= limits.max_connections {
+            return Some("Sorry, the game is full right now, please try again later.".to_string());
+        }
+
+        if limits.max_per_address != 0 {
+            let subnet = subnet_of(addr.ip(), limits.ipv4_prefix, limits.ipv6_prefix);
+            let count = self.connections.keys()
+                .filter(|a| subnet_of(a.ip(), limits.ipv4_prefix, limits.ipv6_prefix) == subnet)
+                .count();
+            if count >= limits.max_per_address {
+                return Some("Too many connections from your address.".to_string());
+            }
+        }
+
+        None
+    }
+
+    fn is_trusted_proxy(&self, addr: SocketAddr) -> bool {
+        let trusted = &self.settings.as_ref().unwrap().proxy.trusted;
+        let allowed = trusted.iter().any(|pattern| bans::cidr_contains(pattern, addr.ip()));
+        if !allowed {
+            log_info(&format!("PROXY listener connection from untrusted {:?}", addr));
+        }
+        allowed
+    }
+
+    // Questions from elsewhere in the server, over the control bus
+    fn answer(&self, request: Request) -> Response {
+        match request {
+            Request::ConnectionCount => Response::ConnectionCount(self.connections.len()),
+            Request::Connections => {
+                let connections = self.connections.iter().map(|(addr, c)| (*addr, c.get_character())).collect();
+                Response::Connections(connections)
+            },
+            // Saving is done by main, the server has nothing of its own to write out
+            Request::SaveAll => Response::Saved,
+        }
+    }
+
     pub fn get_settings(&mut self) -> Option<Settings> {
         return self.settings.clone();
     }
+
+    pub async fn get_status() -> ServerStatus {
+        SERVER_STATUS.read().await.clone()
+    }
+
+    async fn update_status(&self) {
+        SERVER_STATUS.write().await.players = self.connections.len();
+    }
+
+    pub async fn set_wizlock(locked: bool, reason: &str) {
+        log_info(&format!("Game {}: {}", if locked { "wizlocked" } else { "unlocked" }, reason));
+        let mut status = SERVER_STATUS.write().await;
+        status.wizlocked = locked;
+        status.wizlock_reason = reason.to_string();
+    }
+
+    fn addresses(&self) -> Vec<SocketAddr> {
+        self.connections.keys().cloned().collect()
+    }
+
+    /*
+     * Send out whatever is queued for the clients.  Only the server thread
+     * empties the queue, so anything queueing output for every connection
+     * calls this as it goes, rather than waiting on a full queue forever.
+     */
+    async fn flush_queue(&mut self, txreceiver: &mut mpsc::Receiver<NetworkMessage>) {
+        while let Ok(message) = txreceiver.try_recv() {
+            self.send_message(message).await;
+        }
+    }
+
+    // Mortals already playing, who get thrown out by wizlock; anyone still
+    // logging in is stopped by enter_game() instead
+    fn mortal_players(&self) -> Vec<SocketAddr> {
+        self.connections.iter()
+            .filter(|(_, c)| c.get_state() == ConnectionState::Playing && !c.is_immortal())
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+
+// The network an address is in, for counting connections per subnet
+fn subnet_of(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let prefix = ipv4_prefix.min(32) as u32;
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        },
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return subnet_of(IpAddr::V4(v4), ipv4_prefix, ipv6_prefix);
+            }
+            let prefix = ipv6_prefix.min(128) as u32;
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        },
+    }
+}
+
+fn bind_listener(addr: &str) -> io::Result<TcpListener> {
+    let bind_addr: SocketAddr = addr.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let socket = if bind_addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+
+    log_info(&format!("Binding to {}", addr));
+    let _ = socket.set_reuseaddr(true);
+    socket.bind(bind_addr)?;
+    socket.listen(1024)
+}
+
+async fn do_listener_thread(listener: TcpListener, address: String, accept_sender: mpsc::Sender<Accepted>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let accepted = Accepted {
+                    stream,
+                    addr,
+                    listener: address.clone(),
+                    proxied: false,
+                };
+                if accept_sender.send(accepted).await.is_err() {
+                    break;
+                }
+            },
+            Err(e) => {
+                // Usually out of file descriptors, so give it a moment
+                log_error(&format!("Error accepting on {}: {:?}", address, e));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            },
+        }
+    }
+}
+
+// Only loaded when there's a TLS listener to use it
+fn reload_tls_acceptor(settings: &Settings, acceptor: Option<TlsAcceptor>) -> Option<TlsAcceptor> {
+    if !settings.get_listeners().iter().any(|l| l.protocol == Protocol::Tls) {
+        return None;
+    }
+
+    match &settings.tls {
+        Some(tls) => tls::reload_acceptor(tls, acceptor),
+        // Settings::new() won't load a config like this
+        None => acceptor,
+    }
+}
+
+// Checks done on every new connection before anything else happens
+async fn admit_connection(server: &mut Server, stream: TcpStream, addr: SocketAddr) -> Option<TcpStream> {
+    if let Some(reason) = server.check_accept(addr) {
+        reject_connection(stream, addr, reason).await;
+        return None;
+    }
+
+    set_keepalive(&stream, addr, server.get_settings().unwrap().idle.tcp_keepalive);
+    Some(stream)
 }
 
+async fn reject_connection(mut stream: TcpStream, addr: SocketAddr, reason: String) {
+    log_info(&format!("Rejected connection from {:?}: {}", addr, reason));
+    let _ = stream.write_all(format!("{}\r\n", reason).as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
 
-async fn shutdown_stream(item: &mut Arc<RwLock<OwnedWriteHalf>>) {
+async fn shutdown_stream(item: &mut Arc<RwLock<StreamWriter>>) {
     let mutex_clone = Arc::clone(item);
     let _ = { 
         let mut stream = mutex_clone.write().await;
@@ -174,7 +657,7 @@ async fn shutdown_stream(item: &mut Arc<RwLock<OwnedWriteHalf>>) {
     };
 }
 
-async fn write_message(item: &mut Arc<RwLock<OwnedWriteHalf>>, data: &[u8]) {
+async fn write_message(item: &mut Arc<RwLock<StreamWriter>>, data: &[u8]) {
     let mutex_clone = Arc::clone(item);
     let _ = {
         let mut stream = mutex_clone.write().await;
@@ -182,133 +665,357 @@ async fn write_message(item: &mut Arc<RwLock<OwnedWriteHalf>>, data: &[u8]) {
     };
 }
 
-pub async fn do_server_thread(barrier: Arc<Barrier>, shutdown_barrier: Arc<Barrier>,
-                              ctlsender: broadcast::Sender<ControlSignal>) {
+pub fn shutdown_message(request: &ShutdownRequest, when: &str) -> String {
+    if request.reason.is_empty() {
+        format!("The game is {} {}.", request.describe(), when)
+    } else {
+        format!("The game is {} {}: {}", request.describe(), when, request.reason)
+    }
+}
+
+pub fn wizlock_message(reason: &str) -> String {
+    if reason.is_empty() {
+        "The game is closed to mortals right now, please try again later.".to_string()
+    } else {
+        format!("The game is closed to mortals right now: {}", reason)
+    }
+}
+
+pub async fn do_server_thread(mut ctx: TaskContext) {
+    let ctlsender = ctx.ctlsender.clone();
     let mut shutdown = false;
     let mut initialized = false;
     let mut server = { Server::get(None).await.write().await.clone() };
-    let mut listener = server.start_server().clone();
-    let mut ctlqueue = ctlsender.subscribe();
+    let mut tls_acceptor: Option<TlsAcceptor> = None;
+    let mut ctlqueue = ctx.subscribe();
+    let mut idle_ticker = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    let mut countdown_ticker = tokio::time::interval(Duration::from_secs(1));
+    let mut wizlock_kick: Option<Countdown> = None;
+    let mut shutdown_countdown: Option<(Countdown, ShutdownRequest)> = None;
+    let mut farewell = "The game is shutting down now.".to_string();
 
     log_info("Starting server thread");
 
-    let _ = barrier.wait().await;
+    {
+        SERVER_STATUS.write().await.started = SystemTime::now();
+    }
+
+    ctx.ready();
 
     // Shared transmit queue (MUD -> player connection)
-    let (mut txsender, mut txreceiver) = mpsc::channel::<NetworkMessage>(2048);
+    let (txsender, mut txreceiver) = mpsc::channel::<NetworkMessage>(2048);
 
     // Setup receive queue (player connection -> MUD)
     let (rxsender, mut rxreceiver) = mpsc::channel::<NetworkMessage>(2048);
+
+    // New connections from all the listeners
+    let (accept_sender, mut accept_receiver) = mpsc::channel::<Accepted>(256);
+
+    // TLS and WebSocket connections come back here once the handshake is done
+    let (handshake_sender, mut handshake_receiver) = mpsc::channel::<(StreamReader, StreamWriter, SocketAddr, Listener)>(256);
     
-    while !initialized {
-        let ctlmsg = ctlqueue.recv().await.unwrap();
-        match ctlmsg {
-            ControlSignal::Shutdown => shutdown = true,
-            ControlSignal::Reconfigure(new_settings) => {
-                {
-                    server = Server::get(Some(new_settings)).await.write().await.clone();
+    while !initialized && !shutdown {
+        tokio::select! {
+            _ = ctx.stopping() => shutdown = true,
+            v = ctlqueue.recv() => {
+                match v.unwrap_or(ControlSignal::Shutdown) {
+                    ControlSignal::Shutdown => shutdown = true,
+                    ControlSignal::Reconfigure(new_settings) => {
+                        {
+                            server = Server::get(Some(new_settings)).await.write().await.clone();
+                        }
+                        server.update_listeners(&accept_sender);
+                        if server.listeners.is_empty() {
+                            // The supervisor sees us exit, marks us failed and shuts the server down
+                            log_error("Could not open any listeners");
+                            return;
+                        }
+                        tls_acceptor = reload_tls_acceptor(&server.get_settings().unwrap(), None);
+                        Server::set_wizlock(server.wizlocked, &server.wizlock_reason).await;
+                        initialized = true;
+                    },
+                    _ => {},
                 }
-                listener = server.start_server().clone();
-                initialized = true;
             },
-        }           
+        }
+    }
+
+    if let Some(recovery_file) = copyover::take_recovery_file() {
+        if !shutdown {
+            server.recover_connections(&recovery_file, &txsender, &rxsender, &ctlsender).await;
+        }
     }
 
     while !shutdown {
         tokio::select! {
+            _ = ctx.stopping() => shutdown = true,
             v = ctlqueue.recv() => {
+                if v.is_none() {
+                    // Nobody left to tell us to stop
+                    shutdown = true;
+                    continue;
+                }
+
                 match v.unwrap() {
-                    ControlSignal::Shutdown => shutdown = true,
                     ControlSignal::Reconfigure(new_settings) => {
                         log_info("Reconfiguring server thread");
-                        if  new_settings.mud.bind_ip != server.bind_ip || new_settings.mud.port != server.port {
-                            for (_, mut connection) in server.connections.drain() {
-                                connection.disconnect(format!("Server shutting down")).await;
-                            }
-                            task::yield_now().await;
-                            txreceiver.close();
-                           
-                            let mut buffer = vec![];
-                            while txreceiver.recv_many(&mut buffer, 100).await > 0 {
-                                buffer.clear();
-                            }
+                        bans::load_bans(&new_settings.global.data_dir);
+
+                        // Existing TLS sessions keep going, only new ones get the new certificate
+                        tls_acceptor = reload_tls_acceptor(&new_settings, tls_acceptor.clone());
+
+                        // Only a change in the config file overrides wizlock set while running
+                        if new_settings.mud.wizlocked != server.wizlocked || new_settings.mud.wizlock_reason != server.wizlock_reason {
+                            server.wizlocked = new_settings.mud.wizlocked;
+                            server.wizlock_reason = new_settings.mud.wizlock_reason.clone();
+                            Server::set_wizlock(server.wizlocked, &server.wizlock_reason).await;
+                        }
 
-                            (txsender, txreceiver) = mpsc::channel::<NetworkMessage>(2048);
-                            
-                            {
-                                server = Server::get(Some(new_settings)).await.write().await.clone();
+                        server.reconfigure(new_settings).await;
+                        server.update_listeners(&accept_sender);
+                        if server.listeners.is_empty() {
+                            ctx.set_health(Health::Degraded("No listeners open".to_string()));
+                        } else {
+                            ctx.set_health(Health::Healthy);
+                        }
+                    },
+                    ControlSignal::Wizlock(wizlock) => {
+                        Server::set_wizlock(wizlock.locked, &wizlock.reason).await;
+                        wizlock_kick = None;
+                        if wizlock.locked {
+                            wizlock_kick = wizlock.grace.map(Countdown::new);
+                        }
+                    },
+                    ControlSignal::Copyover(by) => {
+                        server.copyover(&by, &mut txreceiver).await;
+                    },
+                    ControlSignal::BeginShutdown(request) => {
+                        let seconds = request.countdown.unwrap_or(server.get_settings().unwrap().shutdown.countdown);
+                        log_info(&format!("Game {} in {} seconds: {}", request.describe(), seconds, request.reason));
+                        farewell = shutdown_message(&request, "now");
+                        shutdown_countdown = Some((Countdown::new(seconds), request));
+                    },
+                    ControlSignal::Broadcast(message) => {
+                        for addr in server.addresses() {
+                            if let Some(connection) = server.connections.get_mut(&addr) {
+                                connection.send_line(&txsender, message.clone()).await;
                             }
-                            listener = server.start_server().clone();
+                            server.flush_queue(&mut txreceiver).await;
                         }
                     },
+                    ControlSignal::ReloadBans => {
+                        bans::load_bans(&server.get_settings().unwrap().global.data_dir);
+                    },
+                    ControlSignal::Request(request, responder) => {
+                        responder.respond(server.answer(request));
+                    },
+                    _ => {},
                 };
             },
-            v = accept_connection(&mut listener) => {
-                let (stream, addr) = v.unwrap();
-                let (rd_half, wr_half) = stream.into_split();
-                let rd_stream = Arc::new(RwLock::new(rd_half));
-                server.rd_streams.insert(addr, rd_stream.clone());
-                server.wr_streams.insert(addr, Arc::new(RwLock::new(wr_half)));
-
-                let rd_ctlrx = ctlsender.subscribe();
-                let rd_dataqueue = rxsender.clone();
-                let rd_handle = tokio::spawn(async move {
-                    do_read_thread(rd_ctlrx, &rd_dataqueue, addr, rd_stream.clone()).await; 
-                });
-                server.rd_handles.insert(addr, Arc::new(RwLock::new(rd_handle)));
-
-                let mut connection = Connection::new(&txsender, addr).await;
-                connection.start_processing().await;
-                server.connections.insert(addr, connection.clone());
-                connection.send_line(&txsender, format!("Hi! $c020PWelcome$c0007 to $c000b{}", server.get_settings().unwrap().mud.name)).await;
+            v = accept_receiver.recv() => {
+                let accepted = v.unwrap();
+                let addr = accepted.addr;
+                let listener = server.listeners.get(&accepted.listener).cloned();
+                if listener.is_none() {
+                    // Accepted just as the listener was closed
+                    continue;
+                }
+
+                let listener = listener.unwrap();
+                if listener.proxy_protocol && !accepted.proxied {
+                    if !server.is_trusted_proxy(addr) {
+                        reject_connection(accepted.stream, addr, "Connection not allowed.".to_string()).await;
+                        continue;
+                    }
+
+                    // Read the header off to the side, then it comes back round with the real address
+                    let sender = accept_sender.clone();
+                    tokio::spawn(async move {
+                        let mut accepted = accepted;
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, proxy::read_header(&mut accepted.stream)).await {
+                            Ok(Ok(real_addr)) => {
+                                if let Some(real_addr) = real_addr {
+                                    log_info(&format!("Proxied connection from {:?} via {:?}", real_addr, addr));
+                                    accepted.addr = real_addr;
+                                }
+                                accepted.proxied = true;
+                                let _ = sender.send(accepted).await;
+                            },
+                            Ok(Err(e)) => log_info(&format!("Bad PROXY header from {:?}: {}", addr, e)),
+                            Err(_) => log_info(&format!("PROXY header from {:?} timed out", addr)),
+                        }
+                    });
+                    continue;
+                }
+
+                if server.connections.contains_key(&addr) {
+                    reject_connection(accepted.stream, addr, "Already connected from this address.".to_string()).await;
+                    continue;
+                }
+
+                let stream = admit_connection(&mut server, accepted.stream, addr).await;
+                if stream.is_none() {
+                    continue;
+                }
+
+                match listener.protocol {
+                    Protocol::Telnet => {
+                        let stream = stream.unwrap();
+                        server.fds.insert(addr, stream.as_raw_fd());
+                        let (rd_half, wr_half) = stream.into_split();
+                        server.add_connection(Box::new(rd_half), Box::new(wr_half), addr, &listener,
+                                              &txsender, &rxsender, &ctlsender, None).await;
+                    },
+                    Protocol::Tls => {
+                        if tls_acceptor.is_none() {
+                            reject_connection(stream.unwrap(), addr, "Secure connections are not available right now.".to_string()).await;
+                            continue;
+                        }
+
+                        // Do the handshake off to the side so a slow client can't hold everyone up
+                        let acceptor = tls_acceptor.clone().unwrap();
+                        let sender = handshake_sender.clone();
+                        tokio::spawn(async move {
+                            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream.unwrap())).await {
+                                Ok(Ok(tls_stream)) => {
+                                    log_info(&format!("TLS connection from {:?}", addr));
+                                    let (rd_half, wr_half) = io::split(tls_stream);
+                                    let _ = sender.send((Box::new(rd_half), Box::new(wr_half), addr, listener)).await;
+                                },
+                                Ok(Err(e)) => log_info(&format!("TLS handshake with {:?} failed: {:?}", addr, e)),
+                                Err(_) => log_info(&format!("TLS handshake with {:?} timed out", addr)),
+                            }
+                        });
+                    },
+                    Protocol::Websocket => {
+                        let sender = handshake_sender.clone();
+                        tokio::spawn(async move {
+                            match tokio::time::timeout(HANDSHAKE_TIMEOUT, websocket::accept(stream.unwrap(), addr)).await {
+                                Ok(Ok(bridge)) => {
+                                    let (rd_half, wr_half) = io::split(bridge);
+                                    let _ = sender.send((Box::new(rd_half), Box::new(wr_half), addr, listener)).await;
+                                },
+                                Ok(Err(e)) => log_info(&format!("WebSocket handshake with {:?} failed: {}", addr, e)),
+                                Err(_) => log_info(&format!("WebSocket handshake with {:?} timed out", addr)),
+                            }
+                        });
+                    },
+                }
+            },
+            v = handshake_receiver.recv() => {
+                let (reader, writer, addr, listener) = v.unwrap();
+                server.add_connection(reader, writer, addr, &listener, &txsender, &rxsender, &ctlsender, None).await;
             },
             v = txreceiver.recv() => {
                 server.send_message(v.unwrap().clone()).await;
             },
+            _ = countdown_ticker.tick() => {
+                if let Some(countdown) = wizlock_kick.as_mut() {
+                    let reason = Server::get_status().await.wizlock_reason;
+                    if countdown.is_done() {
+                        wizlock_kick = None;
+                        for addr in server.mortal_players() {
+                            if let Some(connection) = server.connections.get_mut(&addr) {
+                                connection.disconnect(wizlock_message(&reason)).await;
+                            }
+                            server.flush_queue(&mut txreceiver).await;
+                        }
+                    } else {
+                        if let Some(remaining) = countdown.announcement() {
+                            let message = format!("*** {}  You will be disconnected in {}. ***", wizlock_message(&reason),
+                                                  describe_seconds(remaining));
+                            for addr in server.mortal_players() {
+                                if let Some(connection) = server.connections.get_mut(&addr) {
+                                    connection.send_line(&txsender, message.clone()).await;
+                                }
+                                server.flush_queue(&mut txreceiver).await;
+                            }
+                        }
+                    }
+                }
+
+                if let Some((countdown, request)) = shutdown_countdown.as_mut() {
+                    if countdown.is_done() {
+                        // Everything gets saved before the rest of the server hears about the shutdown
+                        log_info(&format!("Countdown finished, game {}", request.describe()));
+                        shutdown_countdown = None;
+                        let sender = ctlsender.clone();
+                        tokio::spawn(async move {
+                            save_and_shutdown(&sender).await;
+                        });
+                    } else {
+                        if let Some(remaining) = countdown.announcement() {
+                            let when = format!("in {}", describe_seconds(remaining));
+                            let message = format!("*** {} ***", shutdown_message(request, &when));
+                            for addr in server.addresses() {
+                                if let Some(connection) = server.connections.get_mut(&addr) {
+                                    connection.send_line(&txsender, message.clone()).await;
+                                }
+                                server.flush_queue(&mut txreceiver).await;
+                            }
+                        }
+                    }
+                }
+            },
+            _ = idle_ticker.tick() => {
+                for addr in server.addresses() {
+                    if let Some(connection) = server.connections.get_mut(&addr) {
+                        connection.check_idle().await;
+                    }
+                    server.flush_queue(&mut txreceiver).await;
+                }
+            },
             v = rxreceiver.recv() => {
                 server.receive_message(v.unwrap().clone()).await;
             },
         }
     }
 
-    log_info("Closing open connections");
+    server.stop_listeners();
 
-    for (addr, mut connection) in server.connections.clone() {
-        log_info(&format!("Closing connection from {:?}", addr));
-        connection.disconnect("Server shutting down".to_string()).await;
-    }
+    log_info("Closing open connections");
 
-    txreceiver.close();
+    // Clients that won't take their last few lines don't get to hold up the shutdown
+    let deadline = server.get_settings().map(|s| s.shutdown.deadline).unwrap_or(0);
+    let drain = async {
+        for (addr, mut connection) in server.connections.clone() {
+            log_info(&format!("Closing connection from {:?}", addr));
+            connection.disconnect(farewell.clone()).await;
+            server.flush_queue(&mut txreceiver).await;
+        }
 
-    let mut finished = false;
-    while !finished {
-        tokio::select! {
-            v = txreceiver.recv() => {
-                if v.is_none() {
-                    finished = true;
-                } else {
-                    server.send_message(v.unwrap().clone()).await;
-                }
-            },
-        };
+        txreceiver.close();
+        while let Some(message) = txreceiver.recv().await {
+            server.send_message(message).await;
+        }
+    };
+    if deadline == 0 {
+        drain.await;
+    } else if tokio::time::timeout(Duration::from_secs(deadline), drain).await.is_err() {
+        server.force_close().await;
     }
+    server.stop_readers().await;
 
     log_info("Shutting down server thread");
-    let _ = shutdown_barrier.wait().await;
 }
 
-async fn accept_connection(item: &mut Arc<RwLock<Option<TcpListener>>>) -> io::Result<(TcpStream, SocketAddr)> {
-    let mutex_clone = Arc::clone(item);
-    let result = {
-        let listener = mutex_clone.write().await;
-        let result = listener.as_ref().unwrap().accept().await;
-        result
-    };
-    return result;
+// Have the OS notice connections that went away without closing
+fn set_keepalive(stream: &TcpStream, addr: SocketAddr, seconds: u64) {
+    if seconds == 0 {
+        return;
+    }
+
+    let keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(seconds))
+        .with_interval(Duration::from_secs(seconds));
+    let result = SockRef::from(stream).set_tcp_keepalive(&keepalive);
+    if result.is_err() {
+        log_error(&format!("Couldn't set keepalive for {:?}: {:?}", addr, result.err().unwrap()));
+    }
 }
 
-async fn do_read_thread(mut ctlqueue: broadcast::Receiver<ControlSignal>, dataqueue: &mpsc::Sender<NetworkMessage>, 
-                        addr: SocketAddr, stream: Arc<RwLock<OwnedReadHalf>>) {
+async fn do_read_thread(mut ctlqueue: ControlReceiver, dataqueue: &mpsc::Sender<NetworkMessage>, 
+                        addr: SocketAddr, stream: Arc<RwLock<StreamReader>>) {
     let mut shutdown = false;
     let mut buffer = BytesMut::with_capacity(1024);
     let mut rd_stream = stream.write().await;
@@ -316,10 +1023,10 @@ async fn do_read_thread(mut ctlqueue: broadcast::Receiver<ControlSignal>, dataqu
     while !shutdown {
         tokio::select! {
             v = ctlqueue.recv() => {
-                match v.unwrap() {
-                    ControlSignal::Shutdown => shutdown = true,
-                    ControlSignal::Reconfigure(_) => {},
-                };
+                // A shutdown is left to the server thread, which says goodbye first
+                if v.is_none() {
+                    shutdown = true;
+                }
             },
             v = rd_stream.read_buf(&mut buffer) => {
                 if v.is_err() {
@@ -334,6 +1041,7 @@ async fn do_read_thread(mut ctlqueue: broadcast::Receiver<ControlSignal>, dataqu
                         let message = NetworkMessage {
                             dest: addr.clone(),
                             data: buffer[..].to_vec(),
+                            control: StreamControl::None,
                         };
                         let _ = dataqueue.send(message).await;
                     }
@@ -347,6 +1055,7 @@ async fn do_read_thread(mut ctlqueue: broadcast::Receiver<ControlSignal>, dataqu
     let message = NetworkMessage {
         dest: addr.clone(),
         data: vec![],
+        control: StreamControl::None,
     };
     let _ = dataqueue.send(message).await;
 } 
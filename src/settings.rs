@@ -3,7 +3,7 @@ extern crate directories;
 use crate::logging::*;
 use config::{Config, ConfigError, Environment, File};
 use directories::ProjectDirs;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use std::env;
 use std::fs;
 use std::path::Path;
@@ -56,6 +56,166 @@ pub struct Email {
     pub use_ssl: bool,
 }
 
+// What to do when a client goes over one of the input limits
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FloodAction {
+    Truncate,
+    Warn,
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Input {
+    pub max_line_length: usize,
+    pub max_buffered_bytes: usize,
+    pub max_lines_per_second: u32,
+    pub line_burst: u32,
+    pub flood_action: FloodAction,
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Input {
+            max_line_length: 512,
+            max_buffered_bytes: 4096,
+            max_lines_per_second: 10,
+            line_burst: 20,
+            flood_action: FloodAction::Warn,
+        }
+    }
+}
+
+// Telnet command sent to idle connections to check they're still there
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleProbe {
+    Off,
+    Nop,
+    Ayt,
+}
+
+// All times in seconds, 0 to disable
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Idle {
+    pub login_timeout: u64,
+    pub playing_timeout: u64,
+    pub warning_time: u64,
+    pub probe_interval: u64,
+    pub probe: IdleProbe,
+    pub tcp_keepalive: u64,
+}
+
+impl Default for Idle {
+    fn default() -> Self {
+        Idle {
+            login_timeout: 300,
+            playing_timeout: 1800,
+            warning_time: 60,
+            probe_interval: 60,
+            probe: IdleProbe::Nop,
+            tcp_keepalive: 120,
+        }
+    }
+}
+
+/*
+ * Checked when a connection is accepted, 0 for no limit.  Addresses are
+ * counted per subnet of the given prefix length, so a /64 catches someone
+ * hopping around their IPv6 range.
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub max_connections: usize,
+    pub max_per_address: usize,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    pub max_accepts_per_second: u32,
+    pub accept_burst: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: 500,
+            max_per_address: 10,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            max_accepts_per_second: 5,
+            accept_burst: 20,
+        }
+    }
+}
+
+// Certificate chain and key for the TLS listeners, PEM files.  Only needed if there are any.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Tls {
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Telnet,
+    Tls,
+    Websocket,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Listener {
+    pub bind_ip: String,
+    pub port: u16,
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub immortal_only: bool,
+    #[serde(default)]
+    pub builder: bool,
+    // Expect a PROXY protocol header from a load balancer in front of us
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+impl Listener {
+    pub fn address(&self) -> String {
+        if self.bind_ip.contains(':') {
+            format!("[{}]:{}", self.bind_ip, self.port)
+        } else {
+            format!("{}:{}", self.bind_ip, self.port)
+        }
+    }
+}
+
+/*
+ * Seconds of warning the players get before a shutdown or reboot, and how
+ * long to wait for their connections to close cleanly after it before
+ * closing them regardless.
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Shutdown {
+    pub countdown: u64,
+    pub deadline: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            countdown: 60,
+            deadline: 15,
+        }
+    }
+}
+
+// Load balancers allowed to send PROXY headers, in CIDR notation
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Proxy {
+    pub trusted: Vec<String>,
+}
+
 
 #[derive(Debug, Clone, Deserialize)]
 #[allow(unused)]
@@ -67,6 +227,20 @@ pub struct Settings {
     pub redis: Redis,
     pub encryption: Encryption,
     pub email: Email,
+    #[serde(default)]
+    pub input: Input,
+    #[serde(default)]
+    pub idle: Idle,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+    #[serde(default)]
+    pub proxy: Proxy,
+    #[serde(default)]
+    pub shutdown: Shutdown,
 }
 
 impl Settings {
@@ -122,6 +296,26 @@ impl Settings {
             .set_override("global.run_mode", run_mode)?
             .build()?;
 
-        s.try_deserialize()
+        let settings: Settings = s.try_deserialize()?;
+        if settings.tls.is_none() && settings.get_listeners().iter().any(|l| l.protocol == Protocol::Tls) {
+            return Err(ConfigError::Message("tls listeners need cert_file and key_file in [tls]".to_string()));
+        }
+        Ok(settings)
+    }
+
+    // The [[listeners]] list, or just plain telnet on mud.bind_ip and mud.port if there isn't one
+    pub fn get_listeners(&self) -> Vec<Listener> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        vec![Listener {
+            bind_ip: self.mud.bind_ip.clone(),
+            port: self.mud.port,
+            protocol: Protocol::Telnet,
+            immortal_only: false,
+            builder: false,
+            proxy_protocol: false,
+        }]
     }
 }
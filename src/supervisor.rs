@@ -0,0 +1,281 @@
+extern crate tokio;
+
+use crate::control::ControlReceiver;
+use crate::logging::*;
+use crate::ControlSignal;
+use futures_util::future::select_all;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::task::JoinHandle;
+
+// How long a task gets to say it's ready
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a task gets to finish once asked to stop, before it is aborted
+const STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A task that fails more often than this gives up and takes the server down
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(300);
+
+/*
+ * What to do when a task panics, or returns without being asked to stop.
+ * Tasks that keep state the rest of the server depends on (the server
+ * itself, logging) can't simply be started again.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    Restart,
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Health {
+    Starting,
+    Healthy,
+    Degraded(String),
+    Restarting,
+    Failed(String),
+    Stopping,
+    Stopped,
+}
+
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct TaskStatus {
+    pub name: &'static str,
+    pub health: Health,
+    pub restarts: usize,
+    pub started: Instant,
+}
+
+use lazy_static::lazy_static;
+lazy_static! {
+    static ref TASK_STATUS: Arc<RwLock<HashMap<&'static str, TaskStatus>>> = Arc::new(RwLock::new(HashMap::new()));
+}
+
+// For anything outside the supervisor that wants to know what's running
+#[allow(unused)]
+pub fn task_status() -> Vec<TaskStatus> {
+    let mut status: Vec<TaskStatus> = TASK_STATUS.read().unwrap().values().cloned().collect();
+    status.sort_by_key(|s| s.started);
+    status
+}
+
+fn set_health(name: &'static str, health: Health) {
+    let mut status = TASK_STATUS.write().unwrap();
+    if let Some(task) = status.get_mut(name) {
+        task.health = health;
+    }
+}
+
+
+/*
+ * Handed to each task when it is started.  The task calls ready() once it
+ * has set itself up and subscribed to the control bus, and returns when
+ * stopping() does.
+ */
+pub struct TaskContext {
+    pub name: &'static str,
+    pub ctlsender: broadcast::Sender<ControlSignal>,
+    ready: Option<oneshot::Sender<()>>,
+    stop: watch::Receiver<bool>,
+}
+
+impl TaskContext {
+    pub fn ready(&mut self) {
+        if let Some(ready) = self.ready.take() {
+            let _ = ready.send(());
+            set_health(self.name, Health::Healthy);
+        }
+    }
+
+    // This task's end of the control bus
+    pub fn subscribe(&self) -> ControlReceiver {
+        ControlReceiver::new(self.name, &self.ctlsender)
+    }
+
+    // Finishes once the supervisor wants this task to stop
+    pub async fn stopping(&mut self) {
+        while !*self.stop.borrow() {
+            if self.stop.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    #[allow(unused)]
+    pub fn set_health(&self, health: Health) {
+        set_health(self.name, health);
+    }
+}
+
+
+type TaskFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type TaskFactory = Box<dyn FnMut(TaskContext) -> TaskFuture + Send>;
+
+struct Task {
+    name: &'static str,
+    depends_on: Vec<&'static str>,
+    policy: RestartPolicy,
+    factory: TaskFactory,
+    handle: Option<JoinHandle<()>>,
+    stop: Option<watch::Sender<bool>>,
+    failures: Vec<Instant>,
+}
+
+/*
+ * Starts the server's long running tasks in order of their dependencies,
+ * keeps an eye on them while they run, and stops them in the reverse order,
+ * so logging is the first up and the last down.
+ */
+pub struct Supervisor {
+    ctlsender: broadcast::Sender<ControlSignal>,
+    tasks: Vec<Task>,
+    // Indexes into tasks, in the order they were started
+    order: Vec<usize>,
+}
+
+impl Supervisor {
+    pub fn new(ctlsender: &broadcast::Sender<ControlSignal>) -> Self {
+        Supervisor {
+            ctlsender: ctlsender.clone(),
+            tasks: vec![],
+            order: vec![],
+        }
+    }
+
+    pub fn register<F>(&mut self, name: &'static str, depends_on: &[&'static str], policy: RestartPolicy, factory: F)
+        where F: FnMut(TaskContext) -> TaskFuture + Send + 'static {
+        self.tasks.push(Task {
+            name,
+            depends_on: depends_on.to_vec(),
+            policy,
+            factory: Box::new(factory),
+            handle: None,
+            stop: None,
+            failures: vec![],
+        });
+    }
+
+    // Start everything, each task once all the ones it depends on are ready
+    pub async fn start(&mut self) -> Result<(), String> {
+        while self.order.len() < self.tasks.len() {
+            let started: Vec<&'static str> = self.order.iter().map(|&i| self.tasks[i].name).collect();
+            let next = (0..self.tasks.len()).find(|&i| {
+                !self.order.contains(&i) && self.tasks[i].depends_on.iter().all(|d| started.contains(d))
+            });
+            if next.is_none() {
+                let waiting: Vec<&'static str> = (0..self.tasks.len()).filter(|i| !self.order.contains(i))
+                    .map(|i| self.tasks[i].name).collect();
+                return Err(format!("Can't start {:?}, their dependencies are missing or circular", waiting));
+            }
+
+            let index = next.unwrap();
+            self.order.push(index);
+            self.spawn(index).await?;
+        }
+        Ok(())
+    }
+
+    async fn spawn(&mut self, index: usize) -> Result<(), String> {
+        let (ready_sender, ready_receiver) = oneshot::channel::<()>();
+        let (stop_sender, stop_receiver) = watch::channel(false);
+        let task = &mut self.tasks[index];
+        let name = task.name;
+
+        log_info(&format!("Starting task {}", name));
+        TASK_STATUS.write().unwrap().insert(name, TaskStatus {
+            name,
+            health: Health::Starting,
+            restarts: task.failures.len(),
+            started: Instant::now(),
+        });
+
+        let ctx = TaskContext {
+            name,
+            ctlsender: self.ctlsender.clone(),
+            ready: Some(ready_sender),
+            stop: stop_receiver,
+        };
+        task.handle = Some(tokio::spawn((task.factory)(ctx)));
+        task.stop = Some(stop_sender);
+
+        match tokio::time::timeout(STARTUP_TIMEOUT, ready_receiver).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(_)) => Err(format!("Task {} died while starting", name)),
+            Err(_) => Err(format!("Task {} took too long to start", name)),
+        }
+    }
+
+    /*
+     * Wait for a task to end on its own, which it only should when asked to
+     * stop, and deal with it according to its policy.  Meant to be raced
+     * against the control bus until it's time to shut down.
+     */
+    pub async fn watch(&mut self) {
+        let running: Vec<usize> = (0..self.tasks.len()).filter(|&i| self.tasks[i].handle.is_some()).collect();
+        if running.is_empty() {
+            return std::future::pending().await;
+        }
+
+        let (result, which, _) = {
+            let handles = self.tasks.iter_mut().filter_map(|t| t.handle.as_mut());
+            select_all(handles).await
+        };
+
+        let index = running[which];
+        let task = &mut self.tasks[index];
+        task.handle = None;
+        let what = match result {
+            Ok(_) => "exited".to_string(),
+            Err(e) if e.is_panic() => "panicked".to_string(),
+            Err(e) => format!("failed: {:?}", e),
+        };
+        log_error(&format!("Task {} {}", task.name, what));
+
+        let now = Instant::now();
+        task.failures.retain(|t| now.duration_since(*t) < RESTART_WINDOW);
+        task.failures.push(now);
+
+        if task.policy == RestartPolicy::Restart && task.failures.len() <= MAX_RESTARTS {
+            log_info(&format!("Restarting task {} ({} failures recently)", task.name, task.failures.len()));
+            set_health(task.name, Health::Restarting);
+            let result = self.spawn(index).await;
+            if result.is_ok() {
+                return;
+            }
+            log_error(&result.err().unwrap());
+        }
+
+        let name = self.tasks[index].name;
+        set_health(name, Health::Failed(what));
+        log_error(&format!("Task {} can't carry on, shutting down", name));
+        let _ = self.ctlsender.send(ControlSignal::Shutdown);
+    }
+
+    // Stop everything, the most dependent tasks first
+    pub async fn stop(&mut self) {
+        for &index in self.order.iter().rev() {
+            let task = &mut self.tasks[index];
+            if task.handle.is_none() {
+                continue;
+            }
+
+            log_info(&format!("Stopping task {}", task.name));
+            set_health(task.name, Health::Stopping);
+            let _ = task.stop.as_ref().unwrap().send(true);
+
+            let mut handle = task.handle.take().unwrap();
+            if tokio::time::timeout(STOP_TIMEOUT, &mut handle).await.is_err() {
+                log_error(&format!("Task {} didn't stop in time, aborting it", task.name));
+                handle.abort();
+            }
+            set_health(task.name, Health::Stopped);
+        }
+    }
+}
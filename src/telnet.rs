@@ -0,0 +1,740 @@
+use std::collections::HashSet;
+
+/*
+ * Commands defined in RFC854
+ * Options defined in RFC855
+ */
+pub const IAC: u8 = 0xFF;
+pub const DONT: u8 = 0xFE;
+pub const DO: u8 = 0xFD;
+pub const WONT: u8 = 0xFC;
+pub const WILL: u8 = 0xFB;
+pub const SB: u8 = 0xFA;
+pub const GA: u8 = 0xF9;
+pub const EL: u8 = 0xF8;
+pub const EC: u8 = 0xF7;
+pub const AYT: u8 = 0xF6;
+pub const AO: u8 = 0xF5;
+pub const IP: u8 = 0xF4;
+pub const BRK: u8 = 0xF3;
+pub const DM: u8 = 0xF2;
+pub const NOP: u8 = 0xF1;
+pub const SE: u8 = 0xF0;
+pub const EOR: u8 = 0xEF;
+
+pub const TELOPT_ECHO: u8 = 1;
+pub const TELOPT_SGA: u8 = 3;
+pub const TELOPT_TTYPE: u8 = 24;
+pub const TELOPT_EOR: u8 = 25;
+pub const TELOPT_NAWS: u8 = 31;
+pub const TELOPT_CHARSET: u8 = 42;
+pub const TELOPT_MSDP: u8 = 69;
+pub const TELOPT_MSSP: u8 = 70;
+pub const TELOPT_MCCP2: u8 = 86;
+pub const TELOPT_MCCP3: u8 = 87;
+pub const TELOPT_GMCP: u8 = 201;
+
+pub const TTYPE_IS: u8 = 0;
+pub const TTYPE_SEND: u8 = 1;
+
+pub fn command_name(cmd: u8) -> &'static str {
+    match cmd {
+        IAC => "IAC",
+        DONT => "DONT",
+        DO => "DO",
+        WONT => "WONT",
+        WILL => "WILL",
+        SB => "SB",
+        GA => "GA",
+        EL => "EL",
+        EC => "EC",
+        AYT => "AYT",
+        AO => "AO",
+        IP => "IP",
+        BRK => "BRK",
+        DM => "DM",
+        NOP => "NOP",
+        SE => "SE",
+        EOR => "EOR",
+        _ => "UNKNOWN",
+    }
+}
+
+/*
+ * Outgoing data is either text, which must have any 0xFF bytes doubled so
+ * the client doesn't take them as IAC, or a telnet command sequence, which
+ * must go out exactly as built.  Commands can only be made with the
+ * functions below, so the two can't get mixed up.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelnetCommand(Vec<u8>);
+
+impl TelnetCommand {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// IAC cmd, such as IAC GA or IAC NOP
+pub fn command(cmd: u8) -> TelnetCommand {
+    TelnetCommand(vec![IAC, cmd])
+}
+
+pub fn negotiate(cmd: u8, option: u8) -> TelnetCommand {
+    TelnetCommand(vec![IAC, cmd, option])
+}
+
+// IAC SB option <payload> IAC SE, with any 0xFF in the payload doubled
+pub fn subnegotiation(option: u8, payload: &[u8]) -> TelnetCommand {
+    let mut b = vec![IAC, SB, option];
+    b.append(&mut escape_iac(payload));
+    b.push(IAC);
+    b.push(SE);
+    TelnetCommand(b)
+}
+
+pub fn escape_iac(data: &[u8]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(data.len());
+    for &byte in data {
+        b.push(byte);
+        if byte == IAC {
+            b.push(IAC);
+        }
+    }
+    b
+}
+
+
+/*
+ * Option negotiation using the "Q Method" from RFC1143.  Each option has a
+ * state for our side (us) and for the client's side (him), along with a
+ * queue bit that remembers a request made while a negotiation was already
+ * in flight.  This keeps us from ever getting into a negotiation loop with
+ * a misbehaving client.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QState {
+    No,
+    WantNo,
+    WantYes,
+    Yes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QQueue {
+    Empty,
+    Opposite,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OptionState {
+    pub us: QState,
+    pub usq: QQueue,
+    pub him: QState,
+    pub himq: QQueue,
+}
+
+impl Default for OptionState {
+    fn default() -> Self {
+        OptionState {
+            us: QState::No,
+            usq: QQueue::Empty,
+            him: QState::No,
+            himq: QQueue::Empty,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptionChange {
+    LocalEnabled(u8),
+    LocalDisabled(u8),
+    RemoteEnabled(u8),
+    RemoteDisabled(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct TelnetOptions {
+    options: Vec<OptionState>,
+    local_supported: HashSet<u8>,
+    remote_supported: HashSet<u8>,
+}
+
+impl Default for TelnetOptions {
+    fn default() -> Self {
+        TelnetOptions {
+            options: vec![Default::default(); 256],
+            local_supported: HashSet::new(),
+            remote_supported: HashSet::new(),
+        }
+    }
+}
+
+impl TelnetOptions {
+    pub fn new(local_supported: &[u8], remote_supported: &[u8]) -> Self {
+        let mut s: TelnetOptions = Default::default();
+        s.local_supported.extend(local_supported.iter());
+        s.remote_supported.extend(remote_supported.iter());
+        s
+    }
+
+    pub fn get(&self, option: u8) -> OptionState {
+        self.options[option as usize]
+    }
+
+    pub fn local_enabled(&self, option: u8) -> bool {
+        self.options[option as usize].us == QState::Yes
+    }
+
+    pub fn remote_enabled(&self, option: u8) -> bool {
+        self.options[option as usize].him == QState::Yes
+    }
+
+    // The options enabled on our side and on the client's, to carry over a copyover
+    pub fn enabled_options(&self) -> (Vec<u8>, Vec<u8>) {
+        let local = (0..=255u8).filter(|&option| self.local_enabled(option)).collect();
+        let remote = (0..=255u8).filter(|&option| self.remote_enabled(option)).collect();
+        (local, remote)
+    }
+
+    // Put back options the client already agreed to, without negotiating them again
+    pub fn restore(&mut self, local: &[u8], remote: &[u8]) {
+        for &option in local {
+            self.options[option as usize].us = QState::Yes;
+        }
+        for &option in remote {
+            self.options[option as usize].him = QState::Yes;
+        }
+    }
+
+    /*
+     * Each of these returns the command (if any) to send back to the client,
+     * and the resulting change in the enabled state of the option (if any).
+     */
+    pub fn receive(&mut self, cmd: u8, option: u8) -> (Option<TelnetCommand>, Option<OptionChange>) {
+        match cmd {
+            WILL => self.receive_will(option),
+            WONT => self.receive_wont(option),
+            DO => self.receive_do(option),
+            DONT => self.receive_dont(option),
+            _ => (None, None),
+        }
+    }
+
+    pub fn receive_will(&mut self, option: u8) -> (Option<TelnetCommand>, Option<OptionChange>) {
+        let supported = self.remote_supported.contains(&option);
+        let state = &mut self.options[option as usize];
+        let before = state.him == QState::Yes;
+        let response = receive_enable(&mut state.him, &mut state.himq, supported, DO, DONT, option);
+        let after = state.him == QState::Yes;
+        (response, remote_change(option, before, after))
+    }
+
+    pub fn receive_wont(&mut self, option: u8) -> (Option<TelnetCommand>, Option<OptionChange>) {
+        let state = &mut self.options[option as usize];
+        let before = state.him == QState::Yes;
+        let response = receive_disable(&mut state.him, &mut state.himq, DO, DONT, option);
+        let after = state.him == QState::Yes;
+        (response, remote_change(option, before, after))
+    }
+
+    pub fn receive_do(&mut self, option: u8) -> (Option<TelnetCommand>, Option<OptionChange>) {
+        let supported = self.local_supported.contains(&option);
+        let state = &mut self.options[option as usize];
+        let before = state.us == QState::Yes;
+        let response = receive_enable(&mut state.us, &mut state.usq, supported, WILL, WONT, option);
+        let after = state.us == QState::Yes;
+        (response, local_change(option, before, after))
+    }
+
+    pub fn receive_dont(&mut self, option: u8) -> (Option<TelnetCommand>, Option<OptionChange>) {
+        let state = &mut self.options[option as usize];
+        let before = state.us == QState::Yes;
+        let response = receive_disable(&mut state.us, &mut state.usq, WILL, WONT, option);
+        let after = state.us == QState::Yes;
+        (response, local_change(option, before, after))
+    }
+
+    // Ask the client to enable (DO) or disable (DONT) an option on its side
+    pub fn request_remote(&mut self, option: u8, enable: bool) -> (Option<TelnetCommand>, Option<OptionChange>) {
+        let state = &mut self.options[option as usize];
+        let before = state.him == QState::Yes;
+        let response = if enable {
+            request_enable(&mut state.him, &mut state.himq, DO, option)
+        } else {
+            request_disable(&mut state.him, &mut state.himq, DONT, option)
+        };
+        let after = state.him == QState::Yes;
+        (response, remote_change(option, before, after))
+    }
+
+    // Offer to enable (WILL) or disable (WONT) an option on our side
+    pub fn request_local(&mut self, option: u8, enable: bool) -> (Option<TelnetCommand>, Option<OptionChange>) {
+        let state = &mut self.options[option as usize];
+        let before = state.us == QState::Yes;
+        let response = if enable {
+            request_enable(&mut state.us, &mut state.usq, WILL, option)
+        } else {
+            request_disable(&mut state.us, &mut state.usq, WONT, option)
+        };
+        let after = state.us == QState::Yes;
+        (response, local_change(option, before, after))
+    }
+}
+
+fn local_change(option: u8, before: bool, after: bool) -> Option<OptionChange> {
+    match (before, after) {
+        (false, true) => Some(OptionChange::LocalEnabled(option)),
+        (true, false) => Some(OptionChange::LocalDisabled(option)),
+        _ => None,
+    }
+}
+
+fn remote_change(option: u8, before: bool, after: bool) -> Option<OptionChange> {
+    match (before, after) {
+        (false, true) => Some(OptionChange::RemoteEnabled(option)),
+        (true, false) => Some(OptionChange::RemoteDisabled(option)),
+        _ => None,
+    }
+}
+
+// Received WILL (for him) or DO (for us)
+fn receive_enable(state: &mut QState, queue: &mut QQueue, supported: bool,
+                  yes_cmd: u8, no_cmd: u8, option: u8) -> Option<TelnetCommand> {
+    match (*state, *queue) {
+        (QState::No, _) => {
+            if supported {
+                *state = QState::Yes;
+                Some(negotiate(yes_cmd, option))
+            } else {
+                Some(negotiate(no_cmd, option))
+            }
+        },
+        (QState::Yes, _) => None,
+        (QState::WantNo, QQueue::Empty) => {
+            // Error: our refusal was answered by an agreement
+            *state = QState::No;
+            None
+        },
+        (QState::WantNo, QQueue::Opposite) => {
+            // Error: our refusal was answered by an agreement
+            *state = QState::Yes;
+            *queue = QQueue::Empty;
+            None
+        },
+        (QState::WantYes, QQueue::Empty) => {
+            *state = QState::Yes;
+            None
+        },
+        (QState::WantYes, QQueue::Opposite) => {
+            *state = QState::WantNo;
+            *queue = QQueue::Empty;
+            Some(negotiate(no_cmd, option))
+        },
+    }
+}
+
+// Received WONT (for him) or DONT (for us)
+fn receive_disable(state: &mut QState, queue: &mut QQueue, yes_cmd: u8, no_cmd: u8,
+                   option: u8) -> Option<TelnetCommand> {
+    match (*state, *queue) {
+        (QState::No, _) => None,
+        (QState::Yes, _) => {
+            *state = QState::No;
+            Some(negotiate(no_cmd, option))
+        },
+        (QState::WantNo, QQueue::Empty) => {
+            *state = QState::No;
+            None
+        },
+        (QState::WantNo, QQueue::Opposite) => {
+            *state = QState::WantYes;
+            *queue = QQueue::Empty;
+            Some(negotiate(yes_cmd, option))
+        },
+        (QState::WantYes, _) => {
+            *state = QState::No;
+            *queue = QQueue::Empty;
+            None
+        },
+    }
+}
+
+fn request_enable(state: &mut QState, queue: &mut QQueue, yes_cmd: u8, option: u8) -> Option<TelnetCommand> {
+    match (*state, *queue) {
+        (QState::No, _) => {
+            *state = QState::WantYes;
+            Some(negotiate(yes_cmd, option))
+        },
+        (QState::WantNo, QQueue::Empty) => {
+            *queue = QQueue::Opposite;
+            None
+        },
+        (QState::WantYes, QQueue::Opposite) => {
+            *queue = QQueue::Empty;
+            None
+        },
+        // Already enabled, or already negotiating/queued
+        _ => None,
+    }
+}
+
+fn request_disable(state: &mut QState, queue: &mut QQueue, no_cmd: u8, option: u8) -> Option<TelnetCommand> {
+    match (*state, *queue) {
+        (QState::Yes, _) => {
+            *state = QState::WantNo;
+            Some(negotiate(no_cmd, option))
+        },
+        (QState::WantNo, QQueue::Opposite) => {
+            *queue = QQueue::Empty;
+            None
+        },
+        (QState::WantYes, QQueue::Empty) => {
+            *queue = QQueue::Opposite;
+            None
+        },
+        // Already disabled, or already negotiating/queued
+        _ => None,
+    }
+}
+
+
+/*
+ * Streaming decoder for the incoming telnet stream.  This keeps its state
+ * between calls so that IAC sequences and subnegotiations that get split
+ * across multiple reads are still decoded correctly.
+ *
+ * Decoding stops right after IAC SB MCCP3 IAC SE, as everything following
+ * that is compressed.  The undecoded remainder is returned to the caller.
+ */
+const MAX_SUBNEGOTIATION_LEN: usize = 8192;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TelnetEvent {
+    Data(Vec<u8>),
+    Command(u8),
+    Negotiation(u8, u8),
+    Subnegotiation(u8, Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DecoderState {
+    Data,
+    Iac,
+    Negotiation(u8),
+    SbOption,
+    SbData,
+    SbIac,
+}
+
+#[derive(Debug, Clone)]
+pub struct TelnetDecoder {
+    state: DecoderState,
+    sb_option: u8,
+    sb_buffer: Vec<u8>,
+    sb_overflow: bool,
+}
+
+impl Default for TelnetDecoder {
+    fn default() -> Self {
+        TelnetDecoder {
+            state: DecoderState::Data,
+            sb_option: 0,
+            sb_buffer: vec![],
+            sb_overflow: false,
+        }
+    }
+}
+
+impl TelnetDecoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> (Vec<TelnetEvent>, Option<Vec<u8>>) {
+        let mut events = vec![];
+        let mut text: Vec<u8> = vec![];
+
+        for (i, &byte) in data.iter().enumerate() {
+            match self.state {
+                DecoderState::Data => {
+                    if byte == IAC {
+                        self.state = DecoderState::Iac;
+                    } else {
+                        text.push(byte);
+                    }
+                },
+                DecoderState::Iac => {
+                    self.state = DecoderState::Data;
+                    match byte {
+                        IAC => text.push(IAC),
+                        WILL | WONT | DO | DONT => self.state = DecoderState::Negotiation(byte),
+                        SB => self.state = DecoderState::SbOption,
+                        _ => {
+                            flush_text(&mut events, &mut text);
+                            events.push(TelnetEvent::Command(byte));
+                        },
+                    }
+                },
+                DecoderState::Negotiation(cmd) => {
+                    flush_text(&mut events, &mut text);
+                    events.push(TelnetEvent::Negotiation(cmd, byte));
+                    self.state = DecoderState::Data;
+                },
+                DecoderState::SbOption => {
+                    self.sb_option = byte;
+                    self.sb_buffer.clear();
+                    self.sb_overflow = false;
+                    self.state = DecoderState::SbData;
+                },
+                DecoderState::SbData => {
+                    if byte == IAC {
+                        self.state = DecoderState::SbIac;
+                    } else {
+                        self.push_sb(byte);
+                    }
+                },
+                DecoderState::SbIac => {
+                    match byte {
+                        IAC => {
+                            // Escaped 0xFF inside the subnegotiation payload
+                            self.push_sb(IAC);
+                            self.state = DecoderState::SbData;
+                        },
+                        SE => {
+                            flush_text(&mut events, &mut text);
+                            self.finish_sb(&mut events);
+                            self.state = DecoderState::Data;
+
+                            if self.sb_option == TELOPT_MCCP3 {
+                                return (events, Some(data[i + 1..].to_vec()));
+                            }
+                        },
+                        _ => {
+                            // Malformed: the client never sent IAC SE.  End
+                            // the subnegotiation here and treat this as a new
+                            // IAC sequence.
+                            flush_text(&mut events, &mut text);
+                            self.finish_sb(&mut events);
+                            match byte {
+                                WILL | WONT | DO | DONT => self.state = DecoderState::Negotiation(byte),
+                                SB => self.state = DecoderState::SbOption,
+                                _ => {
+                                    events.push(TelnetEvent::Command(byte));
+                                    self.state = DecoderState::Data;
+                                },
+                            }
+                        },
+                    }
+                },
+            }
+        }
+
+        flush_text(&mut events, &mut text);
+        (events, None)
+    }
+
+    fn push_sb(&mut self, byte: u8) {
+        if self.sb_buffer.len() >= MAX_SUBNEGOTIATION_LEN {
+            self.sb_overflow = true;
+        } else {
+            self.sb_buffer.push(byte);
+        }
+    }
+
+    fn finish_sb(&mut self, events: &mut Vec<TelnetEvent>) {
+        let payload: Vec<u8> = self.sb_buffer.drain(..).collect();
+        if !self.sb_overflow {
+            events.push(TelnetEvent::Subnegotiation(self.sb_option, payload));
+        }
+        self.sb_overflow = false;
+    }
+}
+
+fn flush_text(events: &mut Vec<TelnetEvent>, text: &mut Vec<u8>) {
+    if !text.is_empty() {
+        events.push(TelnetEvent::Data(std::mem::take(text)));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed the chunks through one decoder, collecting all the events
+    fn decode_all(chunks: &[&[u8]]) -> Vec<TelnetEvent> {
+        let mut decoder = TelnetDecoder::new();
+        chunks.iter().flat_map(|chunk| decoder.decode(chunk).0).collect()
+    }
+
+    #[test]
+    fn iac_split_across_reads() {
+        let events = decode_all(&[b"ab", &[IAC], &[IAC, b'c']]);
+        assert_eq!(events, vec![TelnetEvent::Data(b"ab".to_vec()), TelnetEvent::Data(vec![IAC, b'c'])]);
+
+        let events = decode_all(&[b"ab", &[IAC], &[GA]]);
+        assert_eq!(events, vec![TelnetEvent::Data(b"ab".to_vec()), TelnetEvent::Command(GA)]);
+    }
+
+    #[test]
+    fn negotiation_split_across_reads() {
+        let events = decode_all(&[&[IAC], &[WILL], &[TELOPT_NAWS], b"x"]);
+        assert_eq!(events, vec![TelnetEvent::Negotiation(WILL, TELOPT_NAWS), TelnetEvent::Data(b"x".to_vec())]);
+    }
+
+    #[test]
+    fn subnegotiation_split_across_reads() {
+        let events = decode_all(&[&[IAC], &[SB], &[TELOPT_NAWS, 0, 80], &[0, 24, IAC], &[SE]]);
+        assert_eq!(events, vec![TelnetEvent::Subnegotiation(TELOPT_NAWS, vec![0, 80, 0, 24])]);
+    }
+
+    #[test]
+    fn escaped_iac_in_subnegotiation() {
+        let events = decode_all(&[&[IAC, SB, TELOPT_NAWS, 0, IAC, IAC, 0, 24, IAC, SE]]);
+        assert_eq!(events, vec![TelnetEvent::Subnegotiation(TELOPT_NAWS, vec![0, IAC, 0, 24])]);
+    }
+
+    #[test]
+    fn mccp3_hands_back_the_rest() {
+        let mut decoder = TelnetDecoder::new();
+        let (events, rest) = decoder.decode(&[b'a', IAC, SB, TELOPT_MCCP3, IAC, SE, 0x78, 0x9c, IAC]);
+        assert_eq!(events, vec![TelnetEvent::Data(b"a".to_vec()), TelnetEvent::Subnegotiation(TELOPT_MCCP3, vec![])]);
+        assert_eq!(rest, Some(vec![0x78, 0x9c, IAC]));
+
+        // Nothing left over when the stream ends right after it
+        let (_, rest) = TelnetDecoder::new().decode(&[IAC, SB, TELOPT_MCCP3, IAC, SE]);
+        assert_eq!(rest, Some(vec![]));
+    }
+
+    #[test]
+    fn unterminated_subnegotiation() {
+        let events = decode_all(&[&[IAC, SB, TELOPT_TTYPE, TTYPE_IS, b'x', IAC, WILL, TELOPT_NAWS, b'y']]);
+        assert_eq!(events, vec![
+            TelnetEvent::Subnegotiation(TELOPT_TTYPE, vec![TTYPE_IS, b'x']),
+            TelnetEvent::Negotiation(WILL, TELOPT_NAWS),
+            TelnetEvent::Data(b"y".to_vec()),
+        ]);
+
+        let events = decode_all(&[&[IAC, SB, TELOPT_TTYPE, b'x', IAC, GA, b'y']]);
+        assert_eq!(events, vec![
+            TelnetEvent::Subnegotiation(TELOPT_TTYPE, vec![b'x']),
+            TelnetEvent::Command(GA),
+            TelnetEvent::Data(b"y".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn oversized_subnegotiation_dropped() {
+        let mut decoder = TelnetDecoder::new();
+        let mut data = vec![IAC, SB, TELOPT_GMCP];
+        data.extend(vec![b'x'; MAX_SUBNEGOTIATION_LEN + 1]);
+        data.extend([IAC, SE, b'a']);
+        let (events, _) = decoder.decode(&data);
+        assert_eq!(events, vec![TelnetEvent::Data(b"a".to_vec())]);
+
+        // The next one gets through
+        let (events, _) = decoder.decode(&[IAC, SB, TELOPT_GMCP, b'y', IAC, SE]);
+        assert_eq!(events, vec![TelnetEvent::Subnegotiation(TELOPT_GMCP, vec![b'y'])]);
+    }
+
+    #[test]
+    fn refuses_unsupported_options() {
+        let mut options = TelnetOptions::new(&[TELOPT_ECHO], &[TELOPT_NAWS]);
+        assert_eq!(options.receive_will(TELOPT_GMCP), (Some(negotiate(DONT, TELOPT_GMCP)), None));
+        assert_eq!(options.receive_do(TELOPT_NAWS), (Some(negotiate(WONT, TELOPT_NAWS)), None));
+        assert_eq!(options.get(TELOPT_GMCP).him, QState::No);
+        assert_eq!(options.get(TELOPT_NAWS).us, QState::No);
+    }
+
+    #[test]
+    fn no_reply_once_enabled() {
+        let mut options = TelnetOptions::new(&[TELOPT_ECHO], &[TELOPT_NAWS]);
+        assert_eq!(options.receive_will(TELOPT_NAWS),
+                   (Some(negotiate(DO, TELOPT_NAWS)), Some(OptionChange::RemoteEnabled(TELOPT_NAWS))));
+        assert_eq!(options.receive_will(TELOPT_NAWS), (None, None));
+
+        assert_eq!(options.receive_do(TELOPT_ECHO),
+                   (Some(negotiate(WILL, TELOPT_ECHO)), Some(OptionChange::LocalEnabled(TELOPT_ECHO))));
+        assert_eq!(options.receive_do(TELOPT_ECHO), (None, None));
+
+        // Turning it off is acknowledged once as well
+        assert_eq!(options.receive_wont(TELOPT_NAWS),
+                   (Some(negotiate(DONT, TELOPT_NAWS)), Some(OptionChange::RemoteDisabled(TELOPT_NAWS))));
+        assert_eq!(options.receive_wont(TELOPT_NAWS), (None, None));
+    }
+
+    #[test]
+    fn request_answered() {
+        let mut options = TelnetOptions::new(&[], &[TELOPT_NAWS]);
+        assert_eq!(options.request_remote(TELOPT_NAWS, true), (Some(negotiate(DO, TELOPT_NAWS)), None));
+        assert_eq!(options.get(TELOPT_NAWS).him, QState::WantYes);
+
+        // Asking again while waiting sends nothing
+        assert_eq!(options.request_remote(TELOPT_NAWS, true), (None, None));
+
+        // The agreement isn't answered
+        assert_eq!(options.receive_will(TELOPT_NAWS), (None, Some(OptionChange::RemoteEnabled(TELOPT_NAWS))));
+
+        let mut options = TelnetOptions::new(&[TELOPT_ECHO], &[]);
+        options.request_local(TELOPT_ECHO, true);
+        assert_eq!(options.receive_dont(TELOPT_ECHO), (None, None));
+        assert_eq!(options.get(TELOPT_ECHO).us, QState::No);
+    }
+
+    #[test]
+    fn queued_disable_while_enabling() {
+        let mut options = TelnetOptions::new(&[], &[TELOPT_NAWS]);
+        options.request_remote(TELOPT_NAWS, true);
+        assert_eq!(options.request_remote(TELOPT_NAWS, false), (None, None));
+        assert_eq!(options.get(TELOPT_NAWS).himq, QQueue::Opposite);
+
+        // The agreement is followed straight away by the queued refusal
+        assert_eq!(options.receive_will(TELOPT_NAWS), (Some(negotiate(DONT, TELOPT_NAWS)), None));
+        let state = options.get(TELOPT_NAWS);
+        assert_eq!((state.him, state.himq), (QState::WantNo, QQueue::Empty));
+
+        assert_eq!(options.receive_wont(TELOPT_NAWS), (None, None));
+        assert_eq!(options.get(TELOPT_NAWS).him, QState::No);
+    }
+
+    #[test]
+    fn queued_enable_while_disabling() {
+        let mut options = TelnetOptions::new(&[], &[TELOPT_NAWS]);
+        options.restore(&[], &[TELOPT_NAWS]);
+        assert_eq!(options.request_remote(TELOPT_NAWS, false),
+                   (Some(negotiate(DONT, TELOPT_NAWS)), Some(OptionChange::RemoteDisabled(TELOPT_NAWS))));
+        assert_eq!(options.request_remote(TELOPT_NAWS, true), (None, None));
+        assert_eq!(options.get(TELOPT_NAWS).himq, QQueue::Opposite);
+
+        assert_eq!(options.receive_wont(TELOPT_NAWS), (Some(negotiate(DO, TELOPT_NAWS)), None));
+        let state = options.get(TELOPT_NAWS);
+        assert_eq!((state.him, state.himq), (QState::WantYes, QQueue::Empty));
+
+        assert_eq!(options.receive_will(TELOPT_NAWS), (None, Some(OptionChange::RemoteEnabled(TELOPT_NAWS))));
+    }
+
+    #[test]
+    fn queued_request_cancelled() {
+        let mut options = TelnetOptions::new(&[TELOPT_ECHO], &[]);
+        options.request_local(TELOPT_ECHO, true);
+        options.request_local(TELOPT_ECHO, false);
+        assert_eq!(options.request_local(TELOPT_ECHO, true), (None, None));
+        assert_eq!(options.get(TELOPT_ECHO).usq, QQueue::Empty);
+
+        assert_eq!(options.receive_do(TELOPT_ECHO), (None, Some(OptionChange::LocalEnabled(TELOPT_ECHO))));
+    }
+
+    #[test]
+    fn restored_options_not_renegotiated() {
+        let mut options = TelnetOptions::new(&[TELOPT_ECHO], &[TELOPT_NAWS]);
+        options.restore(&[TELOPT_ECHO], &[TELOPT_NAWS]);
+        assert!(options.local_enabled(TELOPT_ECHO));
+        assert!(options.remote_enabled(TELOPT_NAWS));
+        assert_eq!(options.enabled_options(), (vec![TELOPT_ECHO], vec![TELOPT_NAWS]));
+
+        assert_eq!(options.receive_do(TELOPT_ECHO), (None, None));
+        assert_eq!(options.receive_will(TELOPT_NAWS), (None, None));
+        assert_eq!(options.request_remote(TELOPT_NAWS, true), (None, None));
+    }
+}
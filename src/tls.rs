@@ -0,0 +1,159 @@
+use crate::logging::*;
+use crate::settings::Tls;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::TlsAcceptor;
+
+/*
+ * Build the TLS acceptor from the PEM certificate chain and private key named
+ * in the settings.  Done again on SIGHUP so renewed certificates get picked
+ * up, while connections already open carry on with the old one.
+ */
+pub fn load_acceptor(tls: &Tls) -> Result<TlsAcceptor, String> {
+    let cert_file = File::open(&tls.cert_file).map_err(|e| format!("{}: {}", tls.cert_file, e))?;
+    let certs: Vec<CertificateDer> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("{}: {}", tls.cert_file, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", tls.cert_file));
+    }
+
+    let key_file = File::open(&tls.key_file).map_err(|e| format!("{}: {}", tls.key_file, e))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+        .map_err(|e| format!("{}: {}", tls.key_file, e))?
+        .ok_or(format!("{}: no private key found", tls.key_file))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Bad certificate or key: {}", e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Keeps the old acceptor if the new certificate won't load
+pub fn reload_acceptor(tls: &Tls, acceptor: Option<TlsAcceptor>) -> Option<TlsAcceptor> {
+    match load_acceptor(tls) {
+        Ok(acceptor) => {
+            log_info(&format!("Loaded TLS certificate from {}", tls.cert_file));
+            Some(acceptor)
+        },
+        Err(e) => {
+            log_error(&format!("Couldn't load TLS certificate: {}", e));
+            acceptor
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    // A fresh self-signed certificate for localhost, written to a directory of its own
+    struct TestCert {
+        dir: PathBuf,
+        cert: CertificateDer<'static>,
+        tls: Tls,
+    }
+
+    impl TestCert {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("havok-tls-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&dir).unwrap();
+
+            let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let cert_file = dir.join("cert.pem");
+            let key_file = dir.join("key.pem");
+            fs::write(&cert_file, generated.cert.pem()).unwrap();
+            fs::write(&key_file, generated.key_pair.serialize_pem()).unwrap();
+
+            TestCert {
+                dir: dir.clone(),
+                cert: generated.cert.der().clone(),
+                tls: Tls {
+                    cert_file: cert_file.to_str().unwrap().to_string(),
+                    key_file: key_file.to_str().unwrap().to_string(),
+                },
+            }
+        }
+    }
+
+    impl Drop for TestCert {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn loads_cert_and_key() {
+        let test = TestCert::new("load");
+        assert!(load_acceptor(&test.tls).is_ok());
+    }
+
+    #[test]
+    fn missing_or_bad_key_is_an_error() {
+        let test = TestCert::new("badkey");
+        let missing = Tls {
+            cert_file: test.tls.cert_file.clone(),
+            key_file: test.dir.join("nonexistent.pem").to_str().unwrap().to_string(),
+        };
+        assert!(load_acceptor(&missing).is_err());
+
+        fs::write(&test.tls.key_file, "not a key").unwrap();
+        assert!(load_acceptor(&test.tls).is_err());
+    }
+
+    // Logging needs a runtime
+    #[tokio::test]
+    async fn reload_keeps_old_acceptor_on_failure() {
+        let test = TestCert::new("reload");
+        let acceptor = reload_acceptor(&test.tls, None).unwrap();
+
+        fs::remove_file(&test.tls.key_file).unwrap();
+        let kept = reload_acceptor(&test.tls, Some(acceptor.clone())).unwrap();
+        assert!(Arc::ptr_eq(kept.config(), acceptor.config()));
+
+        fs::write(&test.tls.key_file, "not a key").unwrap();
+        let kept = reload_acceptor(&test.tls, Some(acceptor.clone())).unwrap();
+        assert!(Arc::ptr_eq(kept.config(), acceptor.config()));
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let test = TestCert::new("handshake");
+        let acceptor = load_acceptor(&test.tls).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(test.cert.clone()).unwrap();
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (client_io, server_io) = tokio::io::duplex(16384);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server_io).await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client = connector.connect(name, client_io).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server.await.unwrap();
+    }
+}
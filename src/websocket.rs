@@ -0,0 +1,334 @@
+use crate::gmcp::GmcpMessage;
+use crate::logging::*;
+use crate::telnet::*;
+use bytes::BytesMut;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/*
+ * WebSocket gateway for browser clients.  The WebSocket is bridged onto an
+ * in-memory stream, so the server end looks just like a telnet socket to
+ * the rest of the server.  The client picks one of two subprotocols:
+ *     telnet - binary frames carrying the raw telnet byte stream, for
+ *              clients that do their own telnet handling
+ *     json   - text frames, one JSON object each:
+ *                  {"text": "look"}                      a line of input or output
+ *                  {"gmcp": "Char.Vitals", "data": {..}} a GMCP message either way
+ *                  {"prompt": true}                      end of a prompt (output only)
+ *              The gateway deals with telnet negotiation itself, and only
+ *              agrees to GMCP.
+ */
+pub const SUBPROTOCOL_TELNET: &str = "telnet";
+pub const SUBPROTOCOL_JSON: &str = "json";
+
+const BRIDGE_BUFFER_SIZE: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Subprotocol {
+    Telnet,
+    Json,
+}
+
+/*
+ * Do the WebSocket handshake on a new connection and start the gateway for
+ * it.  Returns the server's end of the bridge.  A client that doesn't ask
+ * for a subprotocol gets raw telnet.
+ */
+pub async fn accept(stream: TcpStream, addr: SocketAddr) -> Result<DuplexStream, String> {
+    let mut subprotocol = Subprotocol::Telnet;
+
+    // The error type is tungstenite's, and only built when the handshake is refused
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+        let offered = request.headers().get("Sec-WebSocket-Protocol")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+
+        for name in offered.split(',').map(|s| s.trim().to_lowercase()) {
+            let chosen = match name.as_str() {
+                SUBPROTOCOL_TELNET => Some((Subprotocol::Telnet, SUBPROTOCOL_TELNET)),
+                SUBPROTOCOL_JSON => Some((Subprotocol::Json, SUBPROTOCOL_JSON)),
+                _ => None,
+            };
+
+            if let Some((protocol, name)) = chosen {
+                subprotocol = protocol;
+                response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(name));
+                break;
+            }
+        }
+        Ok(response)
+    };
+
+    let websocket = tokio_tungstenite::accept_hdr_async(stream, callback).await.map_err(|e| e.to_string())?;
+    log_info(&format!("WebSocket connection from {:?} using {:?}", addr, subprotocol));
+
+    let (server_end, gateway_end) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+    tokio::spawn(async move {
+        do_gateway_thread(websocket, gateway_end, subprotocol, addr).await;
+    });
+
+    Ok(server_end)
+}
+
+async fn do_gateway_thread(websocket: WebSocketStream<TcpStream>, bridge: DuplexStream, subprotocol: Subprotocol,
+                           addr: SocketAddr) {
+    let (mut ws_tx, mut ws_rx) = websocket.split();
+    let (mut bridge_rx, mut bridge_tx) = tokio::io::split(bridge);
+    let mut translator = JsonTranslator::new();
+    let mut buffer = BytesMut::with_capacity(4096);
+
+    loop {
+        tokio::select! {
+            v = ws_rx.next() => {
+                let data = match v {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Text(text))) => {
+                        if subprotocol == Subprotocol::Json {
+                            translator.inbound(&text)
+                        } else {
+                            text.into_bytes()
+                        }
+                    },
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Err(e)) => {
+                        log_info(&format!("WebSocket error from {:?}: {:?}", addr, e));
+                        break;
+                    },
+                    // Pings are answered for us
+                    Some(Ok(_)) => vec![],
+                };
+
+                if !data.is_empty() && bridge_tx.write_all(&data).await.is_err() {
+                    break;
+                }
+            },
+            v = bridge_rx.read_buf(&mut buffer) => {
+                if v.unwrap_or(0) == 0 {
+                    // The server closed the connection
+                    let _ = ws_tx.send(Message::Close(None)).await;
+                    break;
+                }
+
+                let messages = if subprotocol == Subprotocol::Json {
+                    let (messages, replies) = translator.outbound(&buffer);
+                    if !replies.is_empty() && bridge_tx.write_all(&replies).await.is_err() {
+                        break;
+                    }
+                    messages.into_iter().map(Message::Text).collect()
+                } else {
+                    vec![Message::Binary(buffer.to_vec())]
+                };
+                buffer.clear();
+
+                let mut failed = false;
+                for message in messages {
+                    if ws_tx.send(message).await.is_err() {
+                        failed = true;
+                        break;
+                    }
+                }
+                if failed {
+                    break;
+                }
+            },
+        }
+    }
+
+    log_info(&format!("WebSocket gateway for {:?} closed", addr));
+}
+
+
+/*
+ * Converts between JSON messages and the telnet stream for the json
+ * subprotocol, standing in for a telnet client on the browser's behalf.
+ */
+struct JsonTranslator {
+    decoder: TelnetDecoder,
+    text: Vec<u8>,
+    gmcp: bool,
+}
+
+impl JsonTranslator {
+    fn new() -> Self {
+        JsonTranslator {
+            decoder: TelnetDecoder::new(),
+            text: vec![],
+            gmcp: false,
+        }
+    }
+
+    // A message from the browser, as the bytes a telnet client would have sent
+    fn inbound(&mut self, message: &str) -> Vec<u8> {
+        let value: Value = match serde_json::from_str(message) {
+            Ok(value) => value,
+            Err(_) => return vec![],
+        };
+
+        if let Some(gmcp) = value.get("gmcp").and_then(|v| v.as_str()) {
+            let data = value.get("data").cloned().unwrap_or(Value::Null);
+            return GmcpMessage::new(gmcp, data).encode().as_bytes().to_vec();
+        }
+
+        if let Some(text) = value.get("text").and_then(|v| v.as_str()) {
+            let mut line = escape_iac(text.as_bytes());
+            line.extend_from_slice(b"\r\n");
+            return line;
+        }
+
+        vec![]
+    }
+
+    /*
+     * Telnet output from the server, as JSON messages for the browser, and
+     * any negotiation replies to send back to the server.
+     */
+    fn outbound(&mut self, data: &[u8]) -> (Vec<String>, Vec<u8>) {
+        let mut messages = vec![];
+        let mut replies = vec![];
+        let (events, _) = self.decoder.decode(data);
+
+        for event in events {
+            match event {
+                TelnetEvent::Data(mut text) => {
+                    self.text.append(&mut text);
+                    continue;
+                },
+                _ => self.flush_text(&mut messages, true),
+            }
+
+            match event {
+                TelnetEvent::Command(cmd) if cmd == GA || cmd == EOR => {
+                    messages.push(json!({"prompt": true}).to_string());
+                },
+                TelnetEvent::Negotiation(cmd, option) => {
+                    let reply = match (cmd, option) {
+                        (WILL, TELOPT_GMCP) => {
+                            self.gmcp = true;
+                            Some(negotiate(DO, option))
+                        },
+                        (WONT, TELOPT_GMCP) if self.gmcp => {
+                            self.gmcp = false;
+                            Some(negotiate(DONT, option))
+                        },
+                        (WILL, _) => Some(negotiate(DONT, option)),
+                        (DO, _) => Some(negotiate(WONT, option)),
+                        _ => None,
+                    };
+                    if let Some(reply) = reply {
+                        replies.extend_from_slice(reply.as_bytes());
+                    }
+                },
+                TelnetEvent::Subnegotiation(TELOPT_GMCP, payload) => {
+                    if let Some(message) = GmcpMessage::parse(&payload) {
+                        messages.push(json!({"gmcp": message.package, "data": message.data}).to_string());
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        self.flush_text(&mut messages, false);
+        (messages, replies)
+    }
+
+    // Send on the text so far, holding back a UTF-8 character cut off at the end
+    fn flush_text(&mut self, messages: &mut Vec<String>, all: bool) {
+        let mut end = self.text.len();
+        if !all {
+            let result = std::str::from_utf8(&self.text);
+            if result.is_err() && result.as_ref().err().unwrap().error_len().is_none() {
+                end = result.err().unwrap().valid_up_to();
+            }
+        }
+
+        if end == 0 {
+            return;
+        }
+
+        let text: Vec<u8> = self.text.drain(..end).collect();
+        messages.push(json!({"text": String::from_utf8_lossy(&text)}).to_string());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(messages: &[String]) -> Vec<Value> {
+        messages.iter().map(|m| serde_json::from_str(m).unwrap()).collect()
+    }
+
+    #[test]
+    fn prompts() {
+        let mut translator = JsonTranslator::new();
+        let (messages, _) = translator.outbound(&[b'>', b' ', IAC, GA]);
+        assert_eq!(parsed(&messages), vec![json!({"text": "> "}), json!({"prompt": true})]);
+
+        let (messages, _) = translator.outbound(&[b'>', IAC, EOR, b'x']);
+        assert_eq!(parsed(&messages), vec![json!({"text": ">"}), json!({"prompt": true}), json!({"text": "x"})]);
+    }
+
+    #[test]
+    fn split_utf8_held_back() {
+        let mut translator = JsonTranslator::new();
+        let euro = "€".as_bytes();
+        let (messages, _) = translator.outbound(&[b"price: ", &euro[..2]].concat());
+        assert_eq!(parsed(&messages), vec![json!({"text": "price: "})]);
+
+        let (messages, _) = translator.outbound(&[&euro[2..], b"5"].concat());
+        assert_eq!(parsed(&messages), vec![json!({"text": "€5"})]);
+    }
+
+    #[test]
+    fn gmcp_to_the_browser() {
+        let mut translator = JsonTranslator::new();
+        let (_, replies) = translator.outbound(&[IAC, WILL, TELOPT_GMCP]);
+        assert_eq!(replies, negotiate(DO, TELOPT_GMCP).as_bytes());
+
+        let message = GmcpMessage::new("Char.Vitals", json!({"hp": 100}));
+        let (messages, _) = translator.outbound(message.encode().as_bytes());
+        assert_eq!(parsed(&messages), vec![json!({"gmcp": "Char.Vitals", "data": {"hp": 100}})]);
+
+        let (_, replies) = translator.outbound(&[IAC, WONT, TELOPT_GMCP]);
+        assert_eq!(replies, negotiate(DONT, TELOPT_GMCP).as_bytes());
+    }
+
+    #[test]
+    fn gmcp_from_the_browser() {
+        let mut translator = JsonTranslator::new();
+        let sent = translator.inbound(r#"{"gmcp": "Core.Hello", "data": {"client": "web"}}"#);
+        assert_eq!(sent, GmcpMessage::new("Core.Hello", json!({"client": "web"})).encode().as_bytes());
+
+        let sent = translator.inbound(r#"{"gmcp": "Core.Ping"}"#);
+        assert_eq!(sent, GmcpMessage::new("Core.Ping", Value::Null).encode().as_bytes());
+    }
+
+    #[test]
+    fn text_from_the_browser() {
+        let mut translator = JsonTranslator::new();
+        assert_eq!(translator.inbound(r#"{"text": "say hi"}"#), b"say hi\r\n");
+        assert!(translator.inbound("not json").is_empty());
+    }
+
+    #[test]
+    fn refuses_everything_but_gmcp() {
+        let mut translator = JsonTranslator::new();
+        let (_, replies) = translator.outbound(&[IAC, WILL, TELOPT_ECHO, IAC, DO, TELOPT_NAWS, IAC, DO, TELOPT_GMCP]);
+        assert_eq!(replies, [negotiate(DONT, TELOPT_ECHO).as_bytes(), negotiate(WONT, TELOPT_NAWS).as_bytes(),
+                             negotiate(WONT, TELOPT_GMCP).as_bytes()].concat());
+
+        // Nothing to say when the server turns something off that was never on
+        let (_, replies) = translator.outbound(&[IAC, WONT, TELOPT_ECHO, IAC, DONT, TELOPT_NAWS, IAC, WONT, TELOPT_GMCP]);
+        assert!(replies.is_empty());
+    }
+}